@@ -0,0 +1,459 @@
+//! Settings resolved from, in increasing order of precedence, the built-in
+//! defaults, the config file, `CURRENCY_*` environment variables and flags.
+
+use std::{
+    collections::BTreeMap,
+    env, fmt, fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+use crate::provider::{self, Provider};
+
+// These correspond to what was in config.def.h.
+const API_KEY: &str = ""; // Your API key for Open Exchange Rates. Get your own by signing up at https://openexchangerates.org/signup/free
+
+// Relative path under $HOME
+const FILE_NAME: &str = ".cache/currency.db";
+
+/// Names of the settings, as used in the config file. The matching environment
+/// variable is `CURRENCY_` followed by the name in uppercase and the matching
+/// flag is `--` followed by the name with dashes instead of underscores.
+pub const KEYS: &[&str] = &[
+    "api_key",
+    "api_key_file",
+    "api_url",
+    "max_age",
+    "precision",
+    "provider",
+    "cache_path",
+    "base",
+    "epsilon",
+    "insecure",
+    "max_response_size",
+    "refresh_backoff",
+];
+
+/// Where the effective value of a setting comes from, from lowest to highest precedence.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Source {
+    Default,
+    File,
+    Env,
+    Flag,
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Source::Default => "default",
+            Source::File => "config file",
+            Source::Env => "environment",
+            Source::Flag => "command line",
+        })
+    }
+}
+
+/// How many decimals amounts are printed with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Precision {
+    /// The same number of decimals for every currency.
+    Fixed(usize),
+    /// The decimals of each currency's minor unit, e.g. 2 for USD and 0 for JPY.
+    Auto,
+}
+
+impl FromStr for Precision {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Precision, ()> {
+        match s {
+            "auto" => Ok(Precision::Auto),
+            _ => s.parse().map(Precision::Fixed).map_err(|_| ()),
+        }
+    }
+}
+
+impl fmt::Display for Precision {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Precision::Fixed(decimals) => write!(f, "{}", decimals),
+            Precision::Auto => f.write_str("auto"),
+        }
+    }
+}
+
+/// The effective configuration of a run.
+pub struct Config {
+    /// Config file that was looked for, and whether it was found.
+    pub file: (PathBuf, bool),
+    /// API key sent to the provider.
+    pub api_key: String,
+    /// File the API key was read from, if any.
+    pub api_key_file: Option<PathBuf>,
+    /// Base URL of the provider's API, e.g. a local mock server.
+    pub api_url: String,
+    /// Refresh the cache when it is older than this.
+    pub max_age: Duration,
+    /// Number of decimals in the output.
+    pub precision: Precision,
+    /// Number of decimals for specific currencies, from the `[decimals]` section.
+    pub decimals: BTreeMap<String, usize>,
+    /// Print amounts with the decimals of their currency's minor unit, unless
+    /// the `[decimals]` section gives others, as set by --financial.
+    pub financial: bool,
+    /// Provider the rates are fetched from.
+    pub provider: &'static Provider,
+    /// File the rates are cached in.
+    pub cache_path: PathBuf,
+    /// Currency the fetched rates are expected to be quoted against.
+    pub base: String,
+    /// Amounts and rates in JSON output are rounded to this, unless it is 0.
+    pub epsilon: f64,
+    /// Accept invalid TLS certificates, e.g. those of a local test server.
+    pub insecure: bool,
+    /// Largest response accepted from a provider, in bytes.
+    pub max_response_size: u64,
+    /// How long to wait after a failed refresh before trying again, if not 0.
+    pub refresh_backoff: Duration,
+    /// Where each setting came from, by name.
+    pub sources: BTreeMap<&'static str, Source>,
+}
+
+impl Config {
+    /// Resolves the configuration, with `flags` being (name, value) pairs
+    /// given on the command line.
+    pub fn load(
+        config_file: Option<PathBuf>,
+        flags: &[(&'static str, String)],
+    ) -> Result<Config, String> {
+        let home_dir = env::var("HOME").map_err(|_| "Could not find $HOME environment variable")?;
+
+        let mut raw: BTreeMap<&'static str, (String, Source)> = BTreeMap::new();
+        raw.insert("api_key", (API_KEY.to_string(), Source::Default));
+        raw.insert("api_key_file", (String::new(), Source::Default));
+        raw.insert("api_url", (String::new(), Source::Default)); // depends on the provider
+        raw.insert("max_age", ("1h".to_string(), Source::Default));
+        raw.insert("precision", ("4".to_string(), Source::Default));
+        raw.insert("provider", (provider::DEFAULT.to_string(), Source::Default));
+        raw.insert(
+            "cache_path",
+            (format!("{}/{}", home_dir, FILE_NAME), Source::Default),
+        );
+        raw.insert("base", (String::new(), Source::Default)); // depends on the provider
+        raw.insert("epsilon", ("0".to_string(), Source::Default));
+        raw.insert("insecure", ("false".to_string(), Source::Default));
+        raw.insert("max_response_size", ("4M".to_string(), Source::Default));
+        raw.insert("refresh_backoff", ("0s".to_string(), Source::Default));
+
+        // Config file, if it exists
+        let file = config_file
+            .or_else(|| env::var_os("CURRENCY_CONFIG").map(PathBuf::from))
+            .unwrap_or_else(|| default_config_file(&home_dir));
+        let mut decimals = BTreeMap::new();
+        let found = match fs::read_to_string(&file) {
+            Ok(contents) => {
+                for (key, value) in parse_file(&contents)? {
+                    if let Some(code) = key.strip_prefix("decimals.") {
+                        let count = value.parse().map_err(|_| {
+                            format!("invalid number of decimals '{}' for {}", value, code)
+                        })?;
+                        decimals.insert(code.to_uppercase(), count);
+                        continue;
+                    }
+                    match KEYS.iter().find(|k| **k == key) {
+                        Some(k) => _ = raw.insert(k, (value, Source::File)),
+                        None => {
+                            eprintln!("Warning: unknown setting '{}' in {}.", key, file.display())
+                        }
+                    }
+                }
+                true
+            }
+            Err(_) => false,
+        };
+
+        // Environment variables
+        for key in KEYS {
+            if let Ok(value) = env::var(format!("CURRENCY_{}", key.to_uppercase())) {
+                raw.insert(key, (value, Source::Env));
+            }
+        }
+
+        // Command-line flags
+        for (key, value) in flags {
+            raw.insert(key, (value.clone(), Source::Flag));
+        }
+
+        let value = |key: &str| raw[key].0.as_str();
+        let invalid = |key: &str| {
+            let (value, source) = &raw[key];
+            format!("invalid {} '{}' (from {})", key, value, source)
+        };
+
+        let provider = provider::find(value("provider")).ok_or_else(|| invalid("provider"))?;
+        let expand = |path: &str| match path.strip_prefix("~/") {
+            Some(rest) => PathBuf::from(&home_dir).join(rest),
+            None => PathBuf::from(path),
+        };
+
+        // The key file replaces the key, unless the key was given with a higher precedence
+        let mut api_key = value("api_key").to_string();
+        let mut api_key_file = None;
+        if !value("api_key_file").is_empty() && raw["api_key_file"].1 >= raw["api_key"].1 {
+            let path = expand(value("api_key_file"));
+            check_permissions(&path);
+            let contents = fs::read_to_string(&path).map_err(|e| {
+                format!("unable to read the API key from {} ({})", path.display(), e)
+            })?;
+            api_key = contents.trim().to_string();
+            api_key_file = Some(path);
+        }
+        let mut sources: BTreeMap<_, _> = raw
+            .iter()
+            .map(|(key, (_, source))| (*key, *source))
+            .collect();
+        if api_key_file.is_some() {
+            sources.insert("api_key", raw["api_key_file"].1);
+        }
+
+        Ok(Config {
+            file: (file, found),
+            api_key,
+            api_key_file,
+            api_url: match raw["api_url"].1 {
+                Source::Default => provider.url.to_string(),
+                _ => value("api_url").to_string(),
+            },
+            max_age: parse_duration(value("max_age")).map_err(|_| invalid("max_age"))?,
+            decimals,
+            financial: false,
+            precision: value("precision")
+                .parse()
+                .map_err(|_| invalid("precision"))?,
+            provider,
+            cache_path: expand(value("cache_path")),
+            base: match raw["base"].1 {
+                Source::Default => provider.base.to_string(),
+                _ => value("base").trim().to_uppercase(),
+            },
+            epsilon: match value("epsilon").parse() {
+                Ok(epsilon) if (0.0..1.0).contains(&epsilon) => epsilon,
+                _ => return Err(invalid("epsilon")),
+            },
+            insecure: value("insecure").parse().map_err(|_| invalid("insecure"))?,
+            max_response_size: parse_size(value("max_response_size"))
+                .map_err(|_| invalid("max_response_size"))?,
+            refresh_backoff: parse_duration(value("refresh_backoff"))
+                .map_err(|_| invalid("refresh_backoff"))?,
+            sources,
+        })
+    }
+
+    /// Returns the number of decimals to print amounts of `code` with.
+    ///
+    /// The `[decimals]` section of the config file takes precedence over the
+    /// precision setting, unless the precision was given on the command line.
+    /// With --financial, it takes precedence over the minor unit instead.
+    pub fn decimals(&self, code: &str) -> usize {
+        if (self.financial || self.sources["precision"] != Source::Flag)
+            && let Some(decimals) = self.decimals.get(code)
+        {
+            return *decimals;
+        }
+        if self.financial {
+            return currency::minor_units(code);
+        }
+        match self.precision {
+            Precision::Fixed(decimals) => decimals,
+            Precision::Auto => currency::minor_units(code),
+        }
+    }
+
+    /// Rounds `value`, an amount or a rate, to the decimals of the epsilon
+    /// setting, so that 92.30999999999999 is printed as 92.31 in JSON with an
+    /// epsilon of 1e-9.
+    pub fn snap(&self, value: f64) -> f64 {
+        if self.epsilon == 0.0 {
+            return value;
+        }
+        let decimals = (-self.epsilon.log10()).ceil() as usize;
+        format!("{:.*}", decimals, value).parse().unwrap_or(value)
+    }
+
+    /// Writes every setting to `out` along with where its value comes from.
+    pub fn dump(&self, out: &mut dyn Write) -> io::Result<()> {
+        let (file, found) = &self.file;
+        let status = if *found { "" } else { " (not found)" };
+        writeln!(out, "{:<19}{}{}", "config", file.display(), status)?;
+
+        let api_key = match self.api_key.len() {
+            0 => "(not set)".to_string(),
+            1..=8 => "********".to_string(),
+            len => format!("********{}", self.api_key.get(len - 4..).unwrap_or("")),
+        };
+        let setting = |key: &'static str, value: String| (key, value, self.sources[key]);
+        let mut lines = vec![setting("api_key", api_key)];
+        if let Some(path) = &self.api_key_file {
+            lines.push(setting("api_key_file", path.display().to_string()));
+        }
+        lines.push(setting("api_url", self.api_url.clone()));
+        lines.push(setting("max_age", format_duration(self.max_age)));
+        if self.financial {
+            lines.push(("precision", "auto (--financial)".to_string(), Source::Flag));
+        } else {
+            lines.push(setting("precision", self.precision.to_string()));
+        }
+        if !self.decimals.is_empty() {
+            let decimals: Vec<_> = self
+                .decimals
+                .iter()
+                .map(|(c, n)| format!("{}={}", c, n))
+                .collect();
+            lines.push(("decimals", decimals.join(", "), Source::File));
+        }
+        lines.push(setting("provider", self.provider.name.to_string()));
+        lines.push(setting("cache_path", self.cache_path.display().to_string()));
+        lines.push(setting("base", self.base.clone()));
+        lines.push(setting("epsilon", self.epsilon.to_string()));
+        lines.push(setting("insecure", self.insecure.to_string()));
+        lines.push(setting(
+            "max_response_size",
+            format_size(self.max_response_size),
+        ));
+        lines.push(setting(
+            "refresh_backoff",
+            format_duration(self.refresh_backoff),
+        ));
+        for (key, value, source) in lines {
+            writeln!(out, "{:<19}{:<40}{}", key, value, source)?;
+        }
+        Ok(())
+    }
+}
+
+/// Warns if `path` can be read by users other than its owner, as SSH does for keys.
+#[cfg(unix)]
+fn check_permissions(path: &Path) {
+    use std::os::unix::fs::PermissionsExt;
+
+    if let Ok(metadata) = fs::metadata(path)
+        && metadata.permissions().mode() & 0o077 != 0
+    {
+        eprintln!(
+            "Warning: {} is accessible by other users (mode {:o}), run 'chmod 600 {}'.",
+            path.display(),
+            metadata.permissions().mode() & 0o777,
+            path.display()
+        );
+    }
+}
+
+#[cfg(not(unix))]
+fn check_permissions(_path: &Path) {}
+
+/// Returns `$XDG_CONFIG_HOME/currency/config.toml`, defaulting to `~/.config`.
+fn default_config_file(home_dir: &str) -> PathBuf {
+    let config_dir = env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(home_dir).join(".config"));
+    config_dir.join("currency").join("config.toml")
+}
+
+/// Parses `key = value` lines. Values may be quoted and `#` starts a comment.
+/// Keys following a `[section]` header are returned as `section.key`.
+fn parse_file(contents: &str) -> Result<Vec<(String, String)>, String> {
+    let mut settings = Vec::new();
+    let mut section = String::new();
+    for (index, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            section = format!("{}.", name.trim());
+            continue;
+        }
+        let Some((key, value)) = line.split_once('=') else {
+            return Err(format!(
+                "invalid line {} in config file: '{}'",
+                index + 1,
+                line
+            ));
+        };
+        settings.push((
+            format!("{}{}", section, key.trim()),
+            parse_value(value).to_string(),
+        ));
+    }
+    Ok(settings)
+}
+
+/// Returns the inside of a quoted value, or an unquoted one without its comment.
+pub fn parse_value(value: &str) -> &str {
+    let value = value.trim();
+    match value.strip_prefix('"') {
+        Some(quoted) => quoted
+            .split_once('"')
+            .map(|(inner, _)| inner)
+            .unwrap_or(quoted),
+        None => value.split('#').next().unwrap_or_default().trim(),
+    }
+}
+
+/// Parses a duration such as "90", "30m", "12h" or "7d" (bare numbers are seconds).
+pub fn parse_duration(value: &str) -> Result<Duration, String> {
+    let (number, unit) = match value.find(|c: char| !c.is_ascii_digit()) {
+        Some(index) => value.split_at(index),
+        None => (value, "s"),
+    };
+    let multiplier = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return Err(format!("invalid duration '{}'", value)),
+    };
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("invalid duration '{}'", value))?;
+    Ok(Duration::from_secs(number.saturating_mul(multiplier)))
+}
+
+/// Parses a size such as "512", "64K" or "4M" (bare numbers are bytes).
+pub fn parse_size(value: &str) -> Result<u64, String> {
+    let (number, multiplier) = match value.strip_suffix(['K', 'k']) {
+        Some(number) => (number, 1 << 10),
+        None => match value.strip_suffix('M') {
+            Some(number) => (number, 1 << 20),
+            None => (value, 1),
+        },
+    };
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("invalid size '{}'", value))?;
+    Ok(number.saturating_mul(multiplier))
+}
+
+/// Formats a size with its largest whole unit, e.g. "4M".
+pub fn format_size(bytes: u64) -> String {
+    match bytes {
+        0 => "0".to_string(),
+        _ if bytes.is_multiple_of(1 << 20) => format!("{}M", bytes >> 20),
+        _ if bytes.is_multiple_of(1 << 10) => format!("{}K", bytes >> 10),
+        _ => bytes.to_string(),
+    }
+}
+
+/// Formats a duration with its largest significant unit, e.g. "3d 4h".
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    match secs {
+        0..60 => format!("{}s", secs),
+        60..3600 => format!("{}m", secs / 60),
+        3600..86400 => format!("{}h {}m", secs / 3600, secs % 3600 / 60),
+        _ => format!("{}d {}h", secs / 86400, secs % 86400 / 3600),
+    }
+}
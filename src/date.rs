@@ -0,0 +1,155 @@
+//! Calendar dates, as used by the historical rates endpoints.
+
+use std::{fmt, str::FromStr};
+
+/// A day of the proleptic Gregorian calendar.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Date {
+    pub year: i32,
+    pub month: u32,
+    pub day: u32,
+}
+
+impl Date {
+    /// Returns the UTC date of a Unix timestamp.
+    pub fn from_unix(secs: u64) -> Date {
+        Date::from_days((secs / 86400) as i64)
+    }
+
+    /// Returns the date `days` days after 1970-01-01.
+    pub fn from_days(days: i64) -> Date {
+        // Howard Hinnant's civil_from_days
+        let z = days + 719468;
+        let era = z.div_euclid(146097);
+        let doe = z.rem_euclid(146097);
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+        let year = (yoe + era * 400 + i64::from(month <= 2)) as i32;
+        Date { year, month, day }
+    }
+
+    /// Returns the number of days since 1970-01-01.
+    pub fn days(&self) -> i64 {
+        // Howard Hinnant's days_from_civil
+        let year = i64::from(self.year) - i64::from(self.month <= 2);
+        let era = year.div_euclid(400);
+        let yoe = year.rem_euclid(400);
+        let month = i64::from(self.month);
+        let doy = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5
+            + i64::from(self.day)
+            - 1;
+        let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+        era * 146097 + doe - 719468
+    }
+
+    /// Returns the date `days` days later (or earlier, if negative).
+    pub fn add_days(&self, days: i64) -> Date {
+        Date::from_days(self.days() + days)
+    }
+}
+
+impl FromStr for Date {
+    type Err = String;
+
+    /// Parses a date in the YYYY-MM-DD format.
+    fn from_str(s: &str) -> Result<Date, String> {
+        let invalid = || format!("invalid date '{}', expected YYYY-MM-DD", s);
+        let mut parts = s.splitn(3, '-');
+        let mut next = || {
+            parts
+                .next()
+                .and_then(|p| p.parse().ok())
+                .ok_or_else(invalid)
+        };
+        let year = next()? as i32;
+        let (month, day) = (next()?, next()?);
+        let date = Date { year, month, day };
+        // Reject days that don't exist, such as 2023-02-30
+        if date.add_days(0) != date {
+            return Err(invalid());
+        }
+        Ok(date)
+    }
+}
+
+impl fmt::Display for Date {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:04}-{:02}-{:02}", self.year, self.month, self.day)
+    }
+}
+
+/// Formats a Unix timestamp as e.g. "2024-01-31 16:00 UTC".
+pub fn format_timestamp(secs: u64) -> String {
+    let minutes = secs % 86400 / 60;
+    let date = Date::from_unix(secs);
+    format!("{} {:02}:{:02} UTC", date, minutes / 60, minutes % 60)
+}
+
+/// Formats a number of seconds as an ISO 8601 duration, e.g. "PT1H23M" or
+/// "P2DT4H".
+pub fn format_iso_duration(secs: u64) -> String {
+    let days = secs / 86400;
+    let time: String = [
+        (secs % 86400 / 3600, 'H'),
+        (secs % 3600 / 60, 'M'),
+        (secs % 60, 'S'),
+    ]
+    .iter()
+    .filter(|(value, _)| *value > 0)
+    .map(|(value, unit)| format!("{}{}", value, unit))
+    .collect();
+    match (days, time.is_empty()) {
+        (0, true) => "PT0S".to_string(),
+        (0, false) => format!("PT{}", time),
+        (_, true) => format!("P{}D", days),
+        _ => format!("P{}DT{}", days, time),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Date, format_iso_duration};
+
+    #[test]
+    fn formats_iso_durations() {
+        assert_eq!(format_iso_duration(0), "PT0S");
+        assert_eq!(format_iso_duration(86400), "P1D");
+        assert_eq!(format_iso_duration(86401), "P1DT1S");
+        assert_eq!(format_iso_duration(3600 + 23 * 60), "PT1H23M");
+    }
+
+    #[test]
+    fn parses_dates() {
+        let date: Date = "2024-02-29".parse().unwrap();
+        assert_eq!(
+            date,
+            Date {
+                year: 2024,
+                month: 2,
+                day: 29
+            }
+        );
+        assert_eq!(date.to_string(), "2024-02-29");
+        assert!("2024-1-x".parse::<Date>().is_err());
+    }
+
+    #[test]
+    fn rejects_days_that_dont_exist() {
+        assert!("2023-02-30".parse::<Date>().is_err());
+        assert!("2023-02-29".parse::<Date>().is_err());
+        assert!("2023-13-01".parse::<Date>().is_err());
+    }
+
+    #[test]
+    fn counts_days_from_the_epoch() {
+        assert_eq!(Date::from_days(0).to_string(), "1970-01-01");
+        assert_eq!(Date::from_days(-1).to_string(), "1969-12-31");
+        assert_eq!(Date::from_unix(1704067200).to_string(), "2024-01-01");
+        let date: Date = "2000-03-01".parse().unwrap();
+        assert_eq!(Date::from_days(date.days()), date);
+        assert_eq!(date.add_days(-1).to_string(), "2000-02-29");
+    }
+}
@@ -0,0 +1,196 @@
+//! `currency doctor`, which checks the setup step by step and tells how to fix
+//! what is wrong.
+
+use std::{
+    fs,
+    io::{self, Write},
+    path::Path,
+    time::Instant,
+};
+
+use currency::RateTable;
+
+use crate::{
+    cache_age,
+    config::{Config, Source, format_duration},
+    failed_path, pin_path, provider,
+};
+
+/// How a check went.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Status {
+    Pass,
+    Warn,
+    Fail,
+}
+
+/// The outcome of a check, with a hint on how to fix it unless it passed.
+struct Check {
+    status: Status,
+    message: String,
+    hint: Option<String>,
+}
+
+impl Check {
+    fn pass(message: String) -> Check {
+        Check {
+            status: Status::Pass,
+            message,
+            hint: None,
+        }
+    }
+
+    fn warn(message: String, hint: &str) -> Check {
+        Check {
+            status: Status::Warn,
+            message,
+            hint: Some(hint.to_string()),
+        }
+    }
+
+    fn fail(message: String, hint: &str) -> Check {
+        Check {
+            status: Status::Fail,
+            message,
+            hint: Some(hint.to_string()),
+        }
+    }
+}
+
+/// Runs every check, printing a line for each, then the resolved configuration.
+/// Returns how many checks failed.
+pub fn run(out: &mut dyn Write, config: &Config) -> io::Result<usize> {
+    let checks = [
+        ("API key", check_api_key(config)),
+        ("cache directory", check_cache_dir(&config.cache_path)),
+        ("network", check_network(config)),
+        ("cache", check_cache(config)),
+    ];
+    let mut failed = 0;
+    for (name, check) in &checks {
+        let status = match check.status {
+            Status::Pass => "pass",
+            Status::Warn => "warn",
+            Status::Fail => "FAIL",
+        };
+        writeln!(out, "[{}] {:<17}{}", status, name, check.message)?;
+        if let Some(hint) = &check.hint {
+            writeln!(out, "       {:<17}{}", "", hint)?;
+        }
+        failed += usize::from(check.status == Status::Fail);
+    }
+    writeln!(out)?;
+    writeln!(out, "Configuration:")?;
+    config.dump(out)?;
+    Ok(failed)
+}
+
+fn check_api_key(config: &Config) -> Check {
+    let name = config.provider.name;
+    if !config.provider.requires_key {
+        return Check::pass(format!("not needed by {}", name));
+    }
+    if config.api_key.is_empty() {
+        return Check::fail(
+            format!("{} requires an API key, and none is set", name),
+            "set api_key or api_key_file in the config file, or use --provider frankfurter",
+        );
+    }
+    match config.sources["api_key"] {
+        Source::Default => Check::pass("built in".to_string()),
+        source => Check::pass(format!("set from the {}", source)),
+    }
+}
+
+/// Checks that the cache can be written, in the closest existing directory if
+/// the cache's own doesn't exist yet, as it is created on the first refresh.
+fn check_cache_dir(cache_path: &Path) -> Check {
+    let Some(dir) = cache_path.ancestors().skip(1).find(|dir| dir.is_dir()) else {
+        return Check::fail(
+            format!("no directory of {} exists", cache_path.display()),
+            "set cache_path to a file in a writable directory",
+        );
+    };
+    let probe = dir.join(".currency-doctor");
+    match fs::write(&probe, "") {
+        Ok(()) => {
+            _ = fs::remove_file(&probe);
+            Check::pass(format!("{} is writable", dir.display()))
+        }
+        Err(e) => Check::fail(
+            format!("unable to write in {} ({})", dir.display(), e),
+            "fix the permissions of the directory, or set cache_path to another file",
+        ),
+    }
+}
+
+fn check_network(config: &Config) -> Check {
+    let name = config.provider.name;
+    if config.provider.requires_key && config.api_key.is_empty() {
+        return Check::warn(
+            format!("{} wasn't queried without an API key", name),
+            "set an API key first",
+        );
+    }
+    let url = config.provider.latest_url(&config.api_url, &config.api_key);
+    let started = Instant::now();
+    match provider::probe(config, &url) {
+        Ok(_) => Check::pass(format!(
+            "{} answered in {} ms",
+            name,
+            started.elapsed().as_millis()
+        )),
+        Err(e) => Check::fail(
+            format!("{} at {}: {}", name, config.api_url, e),
+            "check the connection and api_url, or run --providers-status to try the others",
+        ),
+    }
+}
+
+fn check_cache(config: &Config) -> Check {
+    let path = &config.cache_path;
+    let Some(age) = cache_age(path) else {
+        return Check::warn(
+            format!("no rates cached in {} yet", path.display()),
+            "run `currency update`, or any conversion, to fetch them",
+        );
+    };
+    let readable = fs::read_to_string(path)
+        .map_err(|e| e.to_string())
+        .and_then(|json| serde_json::from_str(&json).map_err(|e| e.to_string()))
+        .and_then(|v| RateTable::from_json(&v));
+    if let Err(e) = readable {
+        return Check::fail(
+            format!("{} is unreadable ({})", path.display(), e),
+            "delete it and run `currency update`",
+        );
+    }
+    let old = format_duration(age);
+    if pin_path(path).exists() {
+        return Check::warn(
+            format!("{} old, and pinned", old),
+            "run --unpin-cache to refresh it again",
+        );
+    }
+    if let Some(since) = cache_age(&failed_path(path)) {
+        return Check::warn(
+            format!(
+                "{} old, the last refresh failed {} ago",
+                old,
+                format_duration(since)
+            ),
+            "see the network check, refreshes are held off for refresh_backoff",
+        );
+    }
+    if age >= config.max_age {
+        return Check::warn(
+            format!(
+                "{} old, more than max_age ({})",
+                old,
+                format_duration(config.max_age)
+            ),
+            "run `currency update`, or check why refreshing fails",
+        );
+    }
+    Check::pass(format!("{} old", old))
+}
@@ -0,0 +1,138 @@
+//! Rates published on past days, from the provider's historical endpoint.
+
+use std::{collections::HashSet, error::Error, fs, io::Write, path::PathBuf};
+
+use currency::{RateTable, date::Date};
+use serde_json::Value;
+
+use crate::config::Config;
+
+/// Days --weekend-adjust steps back at most, enough for long holidays.
+const MAX_ADJUST_DAYS: i64 = 7;
+
+/// Block characters used to draw sparklines, from lowest to highest.
+const BARS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+/// Returns the rates published on `date`, downloading them unless they are
+/// already cached. Past rates never change, so cached files are kept forever.
+pub fn fetch(config: &Config, date: Date) -> Result<RateTable, Box<dyn Error>> {
+    let path = cache_file(config, date);
+    if !path.exists() {
+        let url = config
+            .provider
+            .historical_url(&config.api_url, &config.api_key, date);
+        crate::refresh_rates(config, &url, &path)?;
+    }
+    let v: Value = serde_json::from_str(&fs::read_to_string(&path)?)?;
+    Ok(RateTable::from_json(&v)?)
+}
+
+/// Returns the rates published on `date`, along with that date or, with
+/// `adjust`, the closest previous day with rates when `date` has none, as
+/// happens on weekends and holidays.
+pub fn fetch_adjusted(
+    config: &Config,
+    date: Date,
+    adjust: bool,
+) -> Result<(Date, RateTable), Box<dyn Error>> {
+    let attempts = if adjust { MAX_ADJUST_DAYS + 1 } else { 1 };
+    let mut first_error = None;
+    for offset in 0..attempts {
+        let day = date.add_days(-offset);
+        match fetch(config, day) {
+            // The base currency alone, which is always 1, means an empty response
+            Ok(rates) if rates.rates.len() > 1 => return Ok((day, rates)),
+            Ok(_) => _ = first_error.get_or_insert_with(|| "no rates published".into()),
+            Err(e) => _ = first_error.get_or_insert(e),
+        }
+    }
+    Err(first_error.unwrap_or_else(|| "no rates published".into()))
+}
+
+/// Returns where the rates of `date` are cached, next to the latest rates.
+fn cache_file(config: &Config, date: Date) -> PathBuf {
+    config
+        .cache_path
+        .with_extension("history")
+        .join(format!("{}.json", date))
+}
+
+/// Returns the average `from`/`to` rate over the days from `start` to `end`
+/// included, along with how many daily rates it is based on. Days without
+/// data are skipped, as are those for which the provider repeats the rates of
+/// a previous day, like weekends.
+pub fn average_rate(
+    config: &Config,
+    (from, to): (&str, &str),
+    (start, end): (Date, Date),
+) -> Result<(f64, usize), Box<dyn Error>> {
+    let mut published = HashSet::new();
+    let mut rates = Vec::new();
+    for offset in 0..=end.days() - start.days() {
+        let Ok(table) = fetch(config, start.add_days(offset)) else {
+            continue;
+        };
+        if table.rates.len() > 1 && published.insert(table.timestamp) {
+            rates.push(table.lookup(to)? / table.lookup(from)?);
+        }
+    }
+    if rates.is_empty() {
+        return Err(format!("no rates were published from {} to {}", start, end).into());
+    }
+    Ok((rates.iter().sum::<f64>() / rates.len() as f64, rates.len()))
+}
+
+/// Prints a sparkline of the `from`/`to` rate over the last `days` days,
+/// ending with the `latest` rates. Days without data are left blank, unless
+/// `adjust` gives them the rate of the previous day with data.
+pub fn write_sparkline(
+    out: &mut dyn Write,
+    config: &Config,
+    latest: &RateTable,
+    (from, to): (&str, &str),
+    days: u32,
+    adjust: bool,
+) -> Result<(), Box<dyn Error>> {
+    let today = Date::from_unix(latest.timestamp);
+    let mut rates = Vec::new();
+    let mut adjusted = 0;
+    for offset in (1..days).rev() {
+        let date = today.add_days(-i64::from(offset));
+        let rate = match fetch_adjusted(config, date, adjust) {
+            Ok((day, table)) => {
+                adjusted += usize::from(day != date);
+                table.rate(from, to)
+            }
+            Err(_) => None,
+        };
+        rates.push(rate);
+    }
+    rates.push(Some(latest.convert(from, to, 1.0)?));
+
+    let known: Vec<f64> = rates.iter().flatten().copied().collect();
+    let min = known.iter().copied().fold(f64::INFINITY, f64::min);
+    let max = known.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+    let line: String = rates
+        .iter()
+        .map(|rate| match rate {
+            Some(_) if max == min => BARS[BARS.len() / 2],
+            Some(rate) => {
+                let level = (rate - min) / (max - min) * (BARS.len() - 1) as f64;
+                BARS[level.round() as usize]
+            }
+            None => ' ',
+        })
+        .collect();
+
+    let prec = config.decimals(to);
+    write!(out, "{from}/{to} {line} min {min:.prec$} max {max:.prec$}")?;
+    let mut notes = vec![format!("{} days", days)];
+    if adjusted > 0 {
+        notes.push(format!("{} with the rate of an earlier day", adjusted));
+    }
+    if known.len() < rates.len() {
+        notes.push(format!("{} without data", rates.len() - known.len()));
+    }
+    writeln!(out, " ({})", notes.join(", "))?;
+    Ok(())
+}
@@ -0,0 +1,106 @@
+//! Batches of conversions described in a TOML file, for repeatable jobs.
+
+use std::{error::Error, fs, io::Write, path::Path};
+
+use currency::{RateTable, output::ConversionResult};
+
+use crate::{
+    Options,
+    config::{Config, parse_value},
+    normalize_code, round_result, write_result,
+};
+
+/// A conversion requested by a `[[conversions]]` table of a job file.
+#[derive(Debug, Default)]
+struct Request {
+    from: String,
+    to: String,
+    amount: Option<f64>,
+}
+
+/// Runs every conversion of the job file at `path`, such as:
+///
+/// ```toml
+/// [[conversions]]
+/// from = "USD"
+/// to = "EUR"
+/// amount = 100
+/// ```
+///
+/// Conversions that fail are reported with their index, starting at 1, and
+/// make the job fail in the end with --fail-on-missing.
+pub fn run(
+    out: &mut dyn Write,
+    opts: &Options,
+    config: &Config,
+    rates: &RateTable,
+    path: &Path,
+) -> Result<(), Box<dyn Error>> {
+    let contents = fs::read_to_string(path)
+        .map_err(|e| format!("unable to read {} ({})", path.display(), e))?;
+    let requests = parse(&contents).map_err(|e| format!("{}: {}", path.display(), e))?;
+
+    let mut failed = 0;
+    for (index, request) in requests.iter().enumerate() {
+        let (from, to) = (normalize_code(&request.from), normalize_code(&request.to));
+        let amount = request.amount.or(opts.base_amount).unwrap_or(1.0);
+        match rates.convert(&from, &to, amount) {
+            Ok(converted) => {
+                let conversion = ConversionResult {
+                    from: &from,
+                    to: &to,
+                    amount,
+                    converted: round_result(converted, opts, config, &to),
+                    rate: rates.rate(&from, &to).unwrap_or_default(),
+                    timestamp: Some(rates.timestamp),
+                };
+                write_result(out, opts, config, &conversion)?;
+            }
+            Err(e) => {
+                eprintln!("Warning: conversion {}: {}", index + 1, e);
+                failed += 1;
+            }
+        }
+    }
+    if opts.fail_on_missing && failed > 0 {
+        return Err(format!("{} of the conversions failed", failed).into());
+    }
+    Ok(())
+}
+
+/// Parses the `[[conversions]]` tables of a job file.
+fn parse(contents: &str) -> Result<Vec<Request>, String> {
+    let mut requests: Vec<Request> = Vec::new();
+    for (index, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if line == "[[conversions]]" {
+            requests.push(Request::default());
+            continue;
+        }
+        let invalid = || format!("invalid line {}: '{}'", index + 1, line);
+        let (Some((key, value)), Some(request)) = (line.split_once('='), requests.last_mut())
+        else {
+            return Err(invalid());
+        };
+        let value = parse_value(value);
+        match key.trim() {
+            "from" => request.from = value.to_string(),
+            "to" => request.to = value.to_string(),
+            "amount" => request.amount = Some(value.parse().map_err(|_| invalid())?),
+            _ => return Err(invalid()),
+        }
+    }
+    match requests
+        .iter()
+        .position(|r| r.from.is_empty() || r.to.is_empty())
+    {
+        Some(index) => Err(format!(
+            "conversion {} needs both 'from' and 'to'",
+            index + 1
+        )),
+        None => Ok(requests),
+    }
+}
@@ -0,0 +1,405 @@
+//! Currency conversion based on the exchange rates published by providers such
+//! as Open Exchange Rates.
+
+pub mod date;
+pub mod output;
+
+use std::{
+    collections::HashMap,
+    error::Error,
+    fmt,
+    hash::{DefaultHasher, Hash, Hasher},
+};
+
+use serde_json::Value;
+
+use date::Date;
+
+/// Why a conversion failed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConvertError {
+    /// The code isn't in the rate table.
+    UnknownCurrency(String),
+    /// The code is listed with a zero, negative or malformed rate, as published.
+    InvalidRate { code: String, value: String },
+}
+
+impl fmt::Display for ConvertError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConvertError::UnknownCurrency(code) => {
+                write!(f, "'{}' is not recognized as a currency.", code)
+            }
+            ConvertError::InvalidRate { code, value } => {
+                write!(f, "the rate for '{}' is invalid ({}).", code, value)
+            }
+        }
+    }
+}
+
+impl Error for ConvertError {}
+
+/// Exchange rates of every known currency against a common base currency.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RateTable {
+    /// Currency the rates are expressed against, e.g. "USD".
+    pub base: String,
+    /// Unix time at which the rates were published.
+    pub timestamp: u64,
+    /// Units of each currency worth one unit of `base`.
+    pub rates: HashMap<String, f64>,
+    /// Currencies listed with a zero, negative or malformed rate, with that
+    /// value as published.
+    pub invalid: HashMap<String, String>,
+}
+
+impl RateTable {
+    /// Builds a table from the JSON returned by the API.
+    ///
+    /// Entries whose rate is zero, negative or not a number are kept apart in
+    /// `invalid`, so that looking them up tells why they can't be used. APIs
+    /// that omit the base currency from the rates or give a "date" instead of
+    /// a "timestamp", like Frankfurter, are supported too.
+    pub fn from_json(v: &Value) -> Result<RateTable, String> {
+        let Value::Object(entries) = &v["rates"] else {
+            return Err("No 'rates' field found in the JSON data.".to_string());
+        };
+        let mut rates = HashMap::new();
+        let mut invalid = HashMap::new();
+        for (code, rate) in entries {
+            match rate.as_f64() {
+                Some(value) if value.is_finite() && value > 0.0 => {
+                    rates.insert(code.clone(), value);
+                }
+                _ => _ = invalid.insert(code.clone(), rate.to_string()),
+            }
+        }
+
+        let base = v["base"].as_str().unwrap_or_default().to_string();
+        if !base.is_empty() {
+            rates.entry(base.clone()).or_insert(1.0);
+        }
+        let timestamp = match v["timestamp"].as_u64() {
+            Some(timestamp) => timestamp,
+            None => v["date"]
+                .as_str()
+                .and_then(|d| d.parse::<Date>().ok())
+                .map_or(0, |d| d.days().max(0) as u64 * 86400),
+        };
+
+        Ok(RateTable {
+            base,
+            timestamp,
+            rates,
+            invalid,
+        })
+    }
+
+    /// Returns a hash of the base and rates, regardless of when they were
+    /// published, to tell whether a provider actually updated its data.
+    pub fn content_hash(&self) -> u64 {
+        let mut rates: Vec<_> = self.rates.iter().collect();
+        rates.sort_by(|a, b| a.0.cmp(b.0));
+        let mut hasher = DefaultHasher::new();
+        self.base.hash(&mut hasher);
+        for (code, rate) in rates {
+            code.hash(&mut hasher);
+            rate.to_bits().hash(&mut hasher);
+        }
+        hasher.finish()
+    }
+
+    /// Returns how many units of `to` one unit of `from` is worth.
+    pub fn rate(&self, from: &str, to: &str) -> Option<f64> {
+        let rate_from = self.rates.get(from)?;
+        let rate_to = self.rates.get(to)?;
+        Some(rate_to / rate_from)
+    }
+
+    /// Looks up the rate of `code` against the base currency.
+    pub fn lookup(&self, code: &str) -> Result<f64, ConvertError> {
+        if let Some(value) = self.invalid.get(code) {
+            return Err(ConvertError::InvalidRate {
+                code: code.to_string(),
+                value: value.clone(),
+            });
+        }
+        self.rates
+            .get(code)
+            .copied()
+            .ok_or_else(|| ConvertError::UnknownCurrency(code.to_string()))
+    }
+
+    /// Converts `amount` of `from` into `to`: (amount / rate_from) * rate_to
+    pub fn convert(&self, from: &str, to: &str, amount: f64) -> Result<f64, ConvertError> {
+        let rate_from = self.lookup(from)?;
+        let rate_to = self.lookup(to)?;
+        Ok((amount / rate_from) * rate_to)
+    }
+}
+
+/// Converts `amount` of `from` into each of `targets`, by code. Every
+/// conversion succeeds or fails on its own, e.g. if the target is unknown.
+pub fn convert_all(
+    rates: &RateTable,
+    from: &str,
+    amount: f64,
+    targets: &[&str],
+) -> HashMap<String, Result<f64, ConvertError>> {
+    let rate_from = rates.lookup(from);
+    targets
+        .iter()
+        .map(|&to| {
+            let converted = rate_from.clone().and_then(|rate_from| {
+                let rate_to = rates.lookup(to)?;
+                Ok((amount / rate_from) * rate_to)
+            });
+            (to.to_string(), converted)
+        })
+        .collect()
+}
+
+/// Returns the number of decimals of the minor unit of `code` (ISO 4217),
+/// e.g. 2 for USD cents and 0 for JPY. Unlisted codes are assumed to use 2.
+pub fn minor_units(code: &str) -> usize {
+    match code {
+        "BIF" | "CLP" | "DJF" | "GNF" | "ISK" | "JPY" | "KMF" | "KRW" | "PYG" | "RWF" | "UGX"
+        | "UYI" | "VND" | "VUV" | "XAF" | "XOF" | "XPF" => 0,
+        "BHD" | "IQD" | "JOD" | "KWD" | "LYD" | "OMR" | "TND" => 3,
+        "CLF" | "UYW" => 4,
+        _ => 2,
+    }
+}
+
+/// Returns the symbol commonly written before amounts of `code`, if it has
+/// one that isn't ambiguous with a more common currency.
+pub fn symbol(code: &str) -> Option<&'static str> {
+    Some(match code {
+        "USD" => "$",
+        "EUR" => "€",
+        "GBP" => "£",
+        "JPY" => "¥",
+        "INR" => "₹",
+        "KRW" => "₩",
+        "ILS" => "₪",
+        "NGN" => "₦",
+        "PHP" => "₱",
+        "UAH" => "₴",
+        "VND" => "₫",
+        "BTC" => "₿",
+        _ => return None,
+    })
+}
+
+/// Returns the currencies in use in `country`, given as an ISO 3166 code such
+/// as "JP", the main one first. Unlisted countries have none.
+pub fn currencies_of(country: &str) -> &'static [&'static str] {
+    match country {
+        "AD" | "AT" | "BE" | "BG" | "BL" | "CY" | "DE" | "EE" | "ES" | "FI" | "FR" | "GF"
+        | "GP" | "GR" | "HR" | "IE" | "IT" | "LT" | "LU" | "LV" | "MC" | "ME" | "MF" | "MQ"
+        | "MT" | "NL" | "PM" | "PT" | "RE" | "SI" | "SK" | "SM" | "VA" | "XK" | "YT" => &["EUR"],
+        "AE" => &["AED"],
+        "AF" => &["AFN"],
+        "AG" | "AI" | "DM" | "GD" | "KN" | "LC" | "MS" | "VC" => &["XCD"],
+        "AL" => &["ALL"],
+        "AM" => &["AMD"],
+        "AO" => &["AOA"],
+        "AR" => &["ARS"],
+        "AS" | "EC" | "FM" | "GU" | "MH" | "MP" | "PR" | "PW" | "SV" | "TC" | "TL" | "UM"
+        | "US" | "VG" | "VI" => &["USD"],
+        "AU" | "CX" | "KI" | "NF" | "NR" | "TV" => &["AUD"],
+        "AW" => &["AWG"],
+        "AZ" => &["AZN"],
+        "BA" => &["BAM"],
+        "BB" => &["BBD"],
+        "BD" => &["BDT"],
+        "BF" | "BJ" | "CI" | "GW" | "ML" | "NE" | "SN" | "TG" => &["XOF"],
+        "BH" => &["BHD"],
+        "BI" => &["BIF"],
+        "BM" => &["BMD"],
+        "BN" => &["BND"],
+        "BO" => &["BOB"],
+        "BR" => &["BRL"],
+        "BS" => &["BSD"],
+        "BT" => &["BTN", "INR"],
+        "BW" => &["BWP"],
+        "BY" => &["BYN"],
+        "BZ" => &["BZD"],
+        "CA" => &["CAD"],
+        "CD" => &["CDF"],
+        "CF" | "CG" | "CM" | "GA" | "GQ" | "TD" => &["XAF"],
+        "CH" | "LI" => &["CHF"],
+        "CK" | "NU" | "NZ" | "PN" | "TK" => &["NZD"],
+        "CL" => &["CLP"],
+        "CN" => &["CNY"],
+        "CO" => &["COP"],
+        "CR" => &["CRC"],
+        "CU" => &["CUP"],
+        "CV" => &["CVE"],
+        "CW" | "SX" => &["ANG"],
+        "CZ" => &["CZK"],
+        "DJ" => &["DJF"],
+        "DK" | "FO" | "GL" => &["DKK"],
+        "DO" => &["DOP"],
+        "DZ" => &["DZD"],
+        "EG" => &["EGP"],
+        "ER" => &["ERN"],
+        "ET" => &["ETB"],
+        "FJ" => &["FJD"],
+        "GB" | "GG" | "IM" | "JE" => &["GBP"],
+        "GE" => &["GEL"],
+        "GH" => &["GHS"],
+        "GI" => &["GIP"],
+        "GM" => &["GMD"],
+        "GN" => &["GNF"],
+        "GT" => &["GTQ"],
+        "GY" => &["GYD"],
+        "HK" => &["HKD"],
+        "HN" => &["HNL"],
+        "HT" => &["HTG", "USD"],
+        "HU" => &["HUF"],
+        "ID" => &["IDR"],
+        "IL" => &["ILS"],
+        "IN" => &["INR"],
+        "IQ" => &["IQD"],
+        "IR" => &["IRR"],
+        "IS" => &["ISK"],
+        "JM" => &["JMD"],
+        "JO" => &["JOD"],
+        "JP" => &["JPY"],
+        "KE" => &["KES"],
+        "KG" => &["KGS"],
+        "KH" => &["KHR"],
+        "KM" => &["KMF"],
+        "KP" => &["KPW"],
+        "KR" => &["KRW"],
+        "KW" => &["KWD"],
+        "KY" => &["KYD"],
+        "KZ" => &["KZT"],
+        "LA" => &["LAK"],
+        "LB" => &["LBP"],
+        "LK" => &["LKR"],
+        "LR" => &["LRD"],
+        "LS" => &["LSL", "ZAR"],
+        "LY" => &["LYD"],
+        "MA" => &["MAD"],
+        "MD" => &["MDL"],
+        "MG" => &["MGA"],
+        "MK" => &["MKD"],
+        "MM" => &["MMK"],
+        "MN" => &["MNT"],
+        "MO" => &["MOP"],
+        "MR" => &["MRU"],
+        "MU" => &["MUR"],
+        "MV" => &["MVR"],
+        "MW" => &["MWK"],
+        "MX" => &["MXN"],
+        "MY" => &["MYR"],
+        "MZ" => &["MZN"],
+        "NA" => &["NAD", "ZAR"],
+        "NC" | "PF" | "WF" => &["XPF"],
+        "NG" => &["NGN"],
+        "NI" => &["NIO"],
+        "NO" | "SJ" => &["NOK"],
+        "NP" => &["NPR"],
+        "OM" => &["OMR"],
+        "PA" => &["PAB", "USD"],
+        "PE" => &["PEN"],
+        "PG" => &["PGK"],
+        "PH" => &["PHP"],
+        "PK" => &["PKR"],
+        "PL" => &["PLN"],
+        "PS" => &["ILS", "JOD"],
+        "PY" => &["PYG"],
+        "QA" => &["QAR"],
+        "RO" => &["RON"],
+        "RS" => &["RSD"],
+        "RU" => &["RUB"],
+        "RW" => &["RWF"],
+        "SA" => &["SAR"],
+        "SB" => &["SBD"],
+        "SC" => &["SCR"],
+        "SD" => &["SDG"],
+        "SE" => &["SEK"],
+        "SG" => &["SGD"],
+        "SL" => &["SLE"],
+        "SO" => &["SOS"],
+        "SR" => &["SRD"],
+        "SS" => &["SSP"],
+        "ST" => &["STN"],
+        "SY" => &["SYP"],
+        "SZ" => &["SZL"],
+        "TH" => &["THB"],
+        "TJ" => &["TJS"],
+        "TM" => &["TMT"],
+        "TN" => &["TND"],
+        "TO" => &["TOP"],
+        "TR" => &["TRY"],
+        "TT" => &["TTD"],
+        "TW" => &["TWD"],
+        "TZ" => &["TZS"],
+        "UA" => &["UAH"],
+        "UG" => &["UGX"],
+        "UY" => &["UYU"],
+        "UZ" => &["UZS"],
+        "VE" => &["VES"],
+        "VN" => &["VND"],
+        "VU" => &["VUV"],
+        "WS" => &["WST"],
+        "YE" => &["YER"],
+        "ZA" => &["ZAR"],
+        "ZM" => &["ZMW"],
+        "ZW" => &["ZWG", "USD"],
+        _ => &[],
+    }
+}
+
+/// Rounds `values` to `decimals` decimals so that they add up to their rounded
+/// total, as the parts of an invoice should. The units lost by rounding every
+/// value down go to the values that lost the most (largest remainder method).
+pub fn reconcile(values: &[f64], decimals: usize) -> Vec<f64> {
+    let factor = 10f64.powi(decimals as i32);
+    let scaled: Vec<f64> = values.iter().map(|value| value * factor).collect();
+    let mut rounded: Vec<f64> = scaled.iter().map(|s| s.floor()).collect();
+    let total = scaled.iter().sum::<f64>().round();
+    let shortfall = (total - rounded.iter().sum::<f64>()) as usize;
+
+    let mut by_remainder: Vec<usize> = (0..values.len()).collect();
+    by_remainder.sort_by(|&a, &b| (scaled[b] - rounded[b]).total_cmp(&(scaled[a] - rounded[a])));
+    for &index in by_remainder.iter().take(shortfall) {
+        rounded[index] += 1.0;
+    }
+    rounded.iter().map(|r| r / factor).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::reconcile;
+
+    #[test]
+    fn reconciled_parts_add_up_to_the_rounded_total() {
+        let parts = reconcile(&[1.25 * 0.9052; 3], 2);
+        let total: f64 = parts.iter().sum();
+        assert_eq!(
+            format!("{:.2}", total),
+            format!("{:.2}", 3.0 * 1.25 * 0.9052)
+        );
+        assert_eq!(parts, [1.13, 1.13, 1.13]);
+
+        // 4 × 1.1315 = 4.526, one cent more than the parts rounded down
+        let parts = reconcile(&[1.25 * 0.9052; 4], 2);
+        assert_eq!(parts, [1.14, 1.13, 1.13, 1.13]);
+    }
+
+    #[test]
+    fn shortfall_goes_to_the_largest_remainders() {
+        assert_eq!(reconcile(&[0.333, 0.333, 0.334], 2), [0.33, 0.33, 0.34]);
+        assert_eq!(reconcile(&[10.004, 20.006], 2), [10.0, 20.01]);
+    }
+
+    #[test]
+    fn exact_parts_are_left_as_they_are() {
+        assert_eq!(reconcile(&[1.5, 2.25], 2), [1.5, 2.25]);
+        assert_eq!(reconcile(&[], 2), Vec::<f64>::new());
+    }
+}
@@ -0,0 +1,72 @@
+//! Decimal and grouping separators of locales, for the text output.
+
+use std::env;
+
+/// How a locale writes numbers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Separators {
+    /// Separates the integer part from the decimals.
+    pub decimal: char,
+    /// Separates groups of three digits of the integer part, if any.
+    pub group: Option<char>,
+}
+
+impl Separators {
+    /// Looks up the separators of a locale such as "de_DE.UTF-8", "fr" or "C".
+    pub fn for_locale(name: &str) -> Option<Separators> {
+        let name = name.split(['.', '@']).next().unwrap_or_default();
+        let (language, region) = name.split_once(['_', '-']).unwrap_or((name, ""));
+        let (decimal, group) = match (language, region) {
+            ("C" | "POSIX", _) => ('.', None),
+            ("de" | "it", "CH") => ('.', Some('\'')),
+            ("en" | "ja" | "zh" | "ko" | "he" | "th" | "hi" | "ms" | "ga", _) => ('.', Some(',')),
+            (
+                "de" | "es" | "it" | "nl" | "pt" | "da" | "id" | "tr" | "el" | "ro" | "hr" | "sl"
+                | "sr" | "vi",
+                _,
+            ) => (',', Some('.')),
+            (
+                "fr" | "ru" | "pl" | "cs" | "sk" | "sv" | "nb" | "nn" | "no" | "fi" | "uk" | "bg"
+                | "hu" | "lt" | "lv" | "et",
+                _,
+            ) => (',', Some(' ')),
+            _ => return None,
+        };
+        Some(Separators { decimal, group })
+    }
+
+    /// Returns the separators of the locale of the environment, from the
+    /// first of LC_ALL, LC_NUMERIC and LANG that is set, if it is known.
+    pub fn from_env() -> Option<Separators> {
+        let name = ["LC_ALL", "LC_NUMERIC", "LANG"]
+            .iter()
+            .filter_map(|var| env::var(var).ok())
+            .find(|value| !value.is_empty())?;
+        Separators::for_locale(&name)
+    }
+
+    /// Rewrites a number formatted with a period as decimal separator, e.g.
+    /// "-1234.5" becomes "-1.234,5" in German.
+    pub fn apply(&self, formatted: &str) -> String {
+        let Some(start) = formatted.find(|c: char| c.is_ascii_digit()) else {
+            return formatted.to_string();
+        };
+        let end = formatted[start..]
+            .find(|c: char| !c.is_ascii_digit())
+            .map_or(formatted.len(), |index| start + index);
+
+        let mut localized = formatted[..start].to_string();
+        let digits = &formatted[start..end];
+        for (index, digit) in digits.chars().enumerate() {
+            if let Some(group) = self.group
+                && index > 0
+                && (digits.len() - index).is_multiple_of(3)
+            {
+                localized.push(group);
+            }
+            localized.push(digit);
+        }
+        localized.push_str(&formatted[end..].replacen('.', &self.decimal.to_string(), 1));
+        localized
+    }
+}
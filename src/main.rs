@@ -1,142 +1,1791 @@
+mod config;
+mod doctor;
+mod history;
+mod job;
+mod locale;
+mod parallel;
+mod portfolio;
+mod provider;
+mod stream;
+
 use std::{
     env,
     error::Error,
-    fs::{self, OpenOptions},
-    io::Write,
-    path::PathBuf,
-    time::{Duration, SystemTime},
+    fs::{self, File, OpenOptions},
+    io::{self, BufReader, IsTerminal, Read, Write},
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime},
 };
 
+use currency::{
+    RateTable,
+    date::{Date, format_iso_duration, format_timestamp},
+    output::{ConversionResult, OutputFormat},
+};
 use reqwest::blocking as reqwest;
-use serde_json::Value;
+use serde::Serialize;
+use serde_json::{Value, json};
+
+use config::{Config, Source, format_duration, parse_duration};
+use locale::Separators;
+
+// ANSI escape sequences used to highlight the text output
+const COLOR_CODE: &str = "\x1b[1;36m";
+const COLOR_RESULT: &str = "\x1b[1;32m";
+const COLOR_RESET: &str = "\x1b[0m";
+
+// Rates of major currencies, used when neither the provider nor the cache is available
+const FALLBACK_RATES: &str = include_str!("fallback.json");
+
+/// When to highlight the text output with colors.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+enum ColorChoice {
+    /// Only when writing to a terminal and NO_COLOR isn't set.
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+/// A piece of a conversion that can be selected with --fields.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Field {
+    From,
+    To,
+    Amount,
+    Converted,
+    Rate,
+    Timestamp,
+}
+
+impl Field {
+    /// Parses a comma-separated list of field names, e.g. "converted,rate".
+    fn parse_list(list: &str) -> Result<Vec<Field>, String> {
+        list.split(',')
+            .map(|name| match name.trim() {
+                "from" => Ok(Field::From),
+                "to" => Ok(Field::To),
+                "amount" => Ok(Field::Amount),
+                "converted" => Ok(Field::Converted),
+                "rate" => Ok(Field::Rate),
+                "timestamp" => Ok(Field::Timestamp),
+                other => Err(format!("unknown field '{}'", other)),
+            })
+            .collect()
+    }
+}
 
+/// Options given on the command line alongside the positional arguments.
+#[derive(Default)]
+struct Options {
+    /// Round the converted amount to the nearest multiple of this step.
+    round_to: Option<f64>,
+    /// Swap FROM and TO before converting.
+    invert: bool,
+    /// Route the conversion through this intermediary currency.
+    via: Option<String>,
+    /// Refuse to convert with data older than this, even as a fallback.
+    max_stale: Option<Duration>,
+    /// Write results to this file instead of stdout.
+    output_file: Option<PathBuf>,
+    /// Append to the output file rather than overwriting it.
+    append: bool,
+    /// Only load and parse the cache, then exit (not listed in the usage).
+    parse_only: bool,
+    /// Print the state of the cache and exit.
+    cache_info: bool,
+    /// Time fetching, reading and parsing rates, then exit (not listed in the usage).
+    benchmark: bool,
+    /// Read the configuration from this file instead of the default one.
+    config_file: Option<PathBuf>,
+    /// Settings given as flags, overriding the config file and environment.
+    settings: Vec<(&'static str, String)>,
+    /// Print the effective configuration and exit.
+    config_dump: bool,
+    /// Draw the trend of the rate over this many days instead of converting.
+    sparkline: Option<u32>,
+    /// Convert with the average rate over these days, both included.
+    average: Option<(Date, Date)>,
+    /// Use the rates of the previous day with data for days without any.
+    weekend_adjust: bool,
+    /// Format of the results.
+    format: OutputFormat,
+    /// Convert with this rate instead of the cached ones.
+    rate: Option<f64>,
+    /// Run the conversions listed in this TOML file.
+    job: Option<PathBuf>,
+    /// Value the holdings listed in this CSV file instead of converting.
+    portfolio: Option<PathBuf>,
+    /// Refuse to convert a currency into itself.
+    distinct: bool,
+    /// Warn when distinct currencies have the same rate.
+    sanity: bool,
+    /// When to highlight the output with colors.
+    color: ColorChoice,
+    /// Fields printed by the `Fields` format, in order.
+    fields: Vec<Field>,
+    /// Amount converted when none is given, instead of 1.
+    base_amount: Option<f64>,
+    /// Parse the amount with a comma as the decimal separator.
+    decimal_comma: bool,
+    /// Compare the rate of the pair according to these two providers.
+    diff_providers: Option<[&'static provider::Provider; 2]>,
+    /// Print the codes of the known currencies and exit.
+    print_codes: bool,
+    /// Print the currencies used in this country and exit.
+    country: Option<String>,
+    /// List the known providers and exit.
+    list_providers: bool,
+    /// Probe every provider and exit.
+    providers_status: bool,
+    /// Print the whole chain of causes of errors.
+    verbose_errors: bool,
+    /// Tell what refreshing the cache did.
+    verbose: bool,
+    /// Warn about rates moving more than this percentage on a refresh.
+    warn_threshold: Option<f64>,
+    /// Print very large or very small converted values in scientific notation.
+    sci: bool,
+    /// Drop the trailing zeros of amounts, e.g. "92.3" rather than "92.3000".
+    trim_zeros: bool,
+    /// Separators of the locale numbers are written in, instead of a bare period.
+    locale: Option<Separators>,
+    /// Print negative amounts in parentheses, e.g. "(92.31)".
+    accounting: bool,
+    /// Print an approximation of the result, e.g. "about €11.4k".
+    human: bool,
+    /// Round the result half to even, to the minor unit of the currency.
+    financial: bool,
+    /// Convert the line-delimited JSON objects read from stdin.
+    json_stdin: bool,
+    /// Exit with an error after a batch in which some currencies were unknown.
+    fail_on_missing: bool,
+    /// Add how long ago the rates were published to JSON results.
+    age: bool,
+    /// Print the result net of several common fees.
+    fee_tiers: bool,
+    /// Number of threads converting the rows of --portfolio and --json-stdin.
+    jobs: usize,
+    /// Round the parts of a split amount so that they add up to the total.
+    reconcile: bool,
+    /// Save a copy of the cached rates to this file, then exit.
+    snapshot: Option<PathBuf>,
+    /// Convert with the rates in this file instead of the cached ones.
+    rates_file: Option<PathBuf>,
+    /// Protect the cache from refreshes, then exit.
+    pin_cache: bool,
+    /// Allow refreshing the cache again, then exit.
+    unpin_cache: bool,
+}
 
-// These correspond to what was in config.def.h.
-const API_KEY: &str = ""; // Your API key for Open Exchange Rates. Get your own by signing up at https://openexchangerates.org/signup/free
-const API_URL: &str = "https://openexchangerates.org/api/latest.json";
+impl Options {
+    /// Returns whether the text output should be highlighted with colors.
+    fn use_color(&self) -> bool {
+        match self.color {
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+            ColorChoice::Auto => {
+                self.output_file.is_none()
+                    && env::var_os("NO_COLOR").is_none_or(|v| v.is_empty())
+                    && io::stdout().is_terminal()
+            }
+        }
+    }
+}
 
-// Relative path under $HOME
-const FILE_NAME: &str = ".cache/currency.db";
+fn main() {
+    // Parse command-line arguments: currency [OPTIONS] FROM TO amount
+    // Default options from CURRENCY_OPTS come first, so the actual ones override them
+    let mut args: Vec<_> = env::var("CURRENCY_OPTS")
+        .map(|opts| opts.split_whitespace().map(String::from).collect())
+        .unwrap_or_default();
+    args.extend(env::args().skip(1));
+    let (opts, args) = match parse_args(&args) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    if let Err(e) = run(&opts, &args) {
+        eprintln!("Error: {}", e);
+        if opts.verbose_errors {
+            print_causes(e.as_ref());
+        }
+        std::process::exit(1);
+    }
+}
+
+/// Runs what the command line asks for.
+fn run(opts: &Options, args: &[String]) -> Result<(), Box<dyn Error>> {
+    let mut config = Config::load(opts.config_file.clone(), &opts.settings)?;
+    config.financial = opts.financial;
+    if config.insecure {
+        eprintln!(
+            "Warning: TLS certificates are NOT verified (insecure), only use this with test servers."
+        );
+    }
+    if opts.config_dump {
+        config.dump(&mut io::stdout())?;
+        return Ok(());
+    }
+    if opts.list_providers {
+        provider::write_list(&mut io::stdout(), &config)?;
+        return Ok(());
+    }
+    if opts.providers_status {
+        provider::write_status(&mut io::stdout(), &config)?;
+        return Ok(());
+    }
+
+    // Archive the current rates, to convert with them later with --rates-file
+    if let Some(path) = &opts.snapshot {
+        return write_snapshot(&config.cache_path, path);
+    }
+
+    // Keep the current rates, e.g. while a provider publishes bad data
+    if opts.pin_cache || opts.unpin_cache {
+        let pin = pin_path(&config.cache_path);
+        if opts.pin_cache {
+            if !config.cache_path.exists() {
+                return Err(format!(
+                    "there is no cache to pin at {}",
+                    config.cache_path.display()
+                )
+                .into());
+            }
+            fs::write(&pin, "")?;
+            println!(
+                "Pinned {}, it won't be refreshed until --unpin-cache.",
+                config.cache_path.display()
+            );
+        } else {
+            match fs::remove_file(&pin) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+                _ => println!("Unpinned {}.", config.cache_path.display()),
+            }
+        }
+        return Ok(());
+    }
+
+    if opts.cache_info {
+        write_cache_info(&mut io::stdout(), &config)?;
+        return Ok(());
+    }
+
+    // Time each step of getting rates, to spot regressions
+    if opts.benchmark {
+        return benchmark(&config);
+    }
+
+    // Only load and parse the cache, to measure the parsing overhead
+    if opts.parse_only {
+        let rates = read_rates(&config.cache_path)?;
+        println!("{} currencies parsed.", rates.rates.len());
+        return Ok(());
+    }
 
-fn main() -> Result<(), Box<dyn Error>> {
-    // Parse command-line arguments: currency FROM TO amount
-    let args: Vec<_> = env::args().collect();
-    if args.len() != 4 {
-        eprintln!("currency -- Currency converter.");
-        eprintln!("Usage:   currency FROM TO amount");
-        eprintln!("Example: currency USD EUR 123.45");
+    // Check the setup, for when nothing works
+    if let [command] = args
+        && command == "doctor"
+    {
+        let failed = doctor::run(&mut io::stdout(), &config)?;
+        if failed > 0 {
+            return Err(format!("{} of the checks failed", failed).into());
+        }
+        return Ok(());
+    }
+
+    // Only refresh the cache, e.g. from a cron job
+    if let [command] = args
+        && command == "update"
+    {
+        if pin_path(&config.cache_path).exists() {
+            eprintln!("Warning: the cache is pinned, run --unpin-cache to refresh it.");
+            return Ok(());
+        }
+        let url = config.provider.latest_url(&config.api_url, &config.api_key);
+        match refresh_rates(&config, &url, &config.cache_path) {
+            Ok(outcome) => report_refresh(opts, &config, outcome),
+            Err(e) => {
+                eprintln!("Error: unable to refresh currency rates ({}).", e);
+                if opts.verbose_errors {
+                    print_causes(e.as_ref());
+                }
+                std::process::exit(1);
+            }
+        }
+        let rates = read_rates(&config.cache_path)?;
+        println!(
+            "Rates of {} currencies from {} are up to date ({}).",
+            rates.rates.len(),
+            config.provider.name,
+            format_timestamp(rates.timestamp)
+        );
+        return Ok(());
+    }
+
+    // Value every holding of the portfolio in the only currency given
+    if let Some(holdings) = &opts.portfolio {
+        let [to] = args else {
+            print_usage();
+            return Ok(());
+        };
+        let rates = load_rates(&config, opts)?;
+        let mut out = open_output(opts);
+        let to = normalize_code(to);
+        portfolio::write_valuation(&mut out, opts, &config, &rates, holdings, &to)?;
+        return Ok(());
+    }
+
+    // The currencies of a country, which needs no rates
+    if let Some(country) = &opts.country {
+        let codes = currency::currencies_of(country);
+        if codes.is_empty() {
+            return Err(format!("no currency is known for the country '{}'", country).into());
+        }
+        let mut out = open_output(opts);
+        for code in codes {
+            writeln!(out, "{}", code)?;
+        }
+        return Ok(());
+    }
+
+    // Bare codes, e.g. for shell completions
+    if opts.print_codes {
+        let rates = load_rates(&config, opts)?;
+        let mut codes: Vec<&String> = rates.rates.keys().collect();
+        codes.sort();
+        let mut out = open_output(opts);
+        for code in codes {
+            writeln!(out, "{}", code)?;
+        }
+        return Ok(());
+    }
+
+    // Run the conversions listed in a job file
+    if let Some(path) = &opts.job {
+        let rates = load_rates(&config, opts)?;
+        let mut out = open_output(opts);
+        job::run(&mut out, opts, &config, &rates, path)?;
+        return Ok(());
+    }
+
+    // Convert the JSON objects read from stdin, one per line
+    if opts.json_stdin {
+        let rates = load_rates(&config, opts)?;
+        let mut out = open_output(opts);
+        stream::convert_lines(&mut io::stdin().lock(), &mut out, opts, &config, &rates)?;
+        return Ok(());
+    }
+
+    if args.len() < 2 {
+        print_usage();
         return Ok(()); // exit cleanly, like the original code
     }
 
-    let from = args[1].to_uppercase();
-    let to = args[2].to_uppercase();
-    let amount: f64 = args[3].parse().unwrap_or(0.0);
-
-    // Build the path: $HOME + FILE_NAME
-    let home_dir = env::var("HOME").expect("Could not find $HOME environment variable");
-    let mut file_path = PathBuf::from(home_dir);
-    file_path.push(FILE_NAME);
-
-    // Decide if we need to refresh the cache
-    let mut need_refresh = true;
-    if let Ok(metadata) = fs::metadata(&file_path) {
-        if let Ok(mtime) = metadata.modified() {
-            // Compare modification time with current time
-            let now = SystemTime::now();
-            if let Ok(age) = now.duration_since(mtime) {
-                if age < Duration::from_secs(3600) {
-                    // If less than 1 hour old, do not refresh
-                    need_refresh = false;
+    let mut from = normalize_code(&args[0]);
+    let mut to = normalize_code(&args[1]);
+    if opts.invert {
+        std::mem::swap(&mut from, &mut to);
+    }
+    // Identical codes are likely a copy-paste mistake for some workflows
+    if opts.distinct && to.split(',').any(|target| target.trim() == from) {
+        return Err(format!("FROM and TO are both {} (--distinct)", from).into());
+    }
+    let amounts: Vec<f64> = match &args[2..] {
+        [] => vec![opts.base_amount.unwrap_or(1.0)],
+        amounts => amounts
+            .iter()
+            .map(|amount| parse_amount(amount, opts.decimal_comma))
+            .collect::<Result<_, _>>()?,
+    };
+    let amount = amounts[0];
+
+    let mut out = open_output(opts);
+
+    // Several targets, e.g. "EUR,GBP,JPY", are converted into one after the other
+    if to.contains(',') {
+        if amounts.len() > 1 {
+            return Err("several amounts can't be converted into several currencies".into());
+        }
+        let rates = load_rates(&config, opts)?;
+        rates.lookup(&from)?;
+        let targets: Vec<&str> = to
+            .split(',')
+            .map(str::trim)
+            .filter(|t| !t.is_empty())
+            .collect();
+        let results = currency::convert_all(&rates, &from, amount, &targets);
+        let mut missing = 0;
+        for target in &targets {
+            match &results[*target] {
+                Ok(converted) => {
+                    let conversion = ConversionResult {
+                        from: &from,
+                        to: target,
+                        amount,
+                        converted: round_result(*converted, opts, &config, target),
+                        rate: rates.rate(&from, target).unwrap_or_default(),
+                        timestamp: Some(rates.timestamp),
+                    };
+                    write_result(&mut out, opts, &config, &conversion)?;
+                }
+                Err(e) => {
+                    eprintln!("Warning: {}", e);
+                    missing += 1;
                 }
             }
         }
+        if opts.fail_on_missing && missing > 0 {
+            return Err(format!("{} of the targets couldn't be converted into", missing).into());
+        }
+        return Ok(());
+    }
+
+    // Several amounts are converted with the same rate, then added up
+    if amounts.len() > 1 {
+        let (rate, timestamp) = if from == to {
+            (1.0, None)
+        } else if let Some(rate) = opts.rate {
+            (rate, None)
+        } else {
+            let rates = load_rates(&config, opts)?;
+            let rate = rates.lookup(&to)? / rates.lookup(&from)?;
+            (rate, Some(rates.timestamp))
+        };
+        let mut parts: Vec<ConversionResult> = amounts
+            .iter()
+            .map(|&amount| ConversionResult {
+                from: &from,
+                to: &to,
+                amount,
+                converted: amount * rate,
+                rate,
+                timestamp,
+            })
+            .collect();
+        write_parts(&mut out, opts, &config, &mut parts)?;
+        return Ok(());
+    }
+
+    // Compare what two providers currently publish for the pair
+    if let Some(providers) = opts.diff_providers {
+        provider::write_diff(&mut out, &config, (&from, &to), providers)?;
+        return Ok(());
+    }
+
+    // Converting a currency to itself needs no rates at all
+    if from == to && opts.via.is_none() {
+        let identity = ConversionResult {
+            from: &from,
+            to: &to,
+            amount,
+            converted: round_result(amount, opts, &config, &to),
+            rate: 1.0,
+            timestamp: None,
+        };
+        write_result(&mut out, opts, &config, &identity)?;
+        return Ok(());
+    }
+
+    // A rate given on the command line needs neither the network nor the cache
+    if let Some(rate) = opts.rate {
+        let fixed = ConversionResult {
+            from: &from,
+            to: &to,
+            amount,
+            converted: round_result(amount * rate, opts, &config, &to),
+            rate,
+            timestamp: None,
+        };
+        write_result(&mut out, opts, &config, &fixed)?;
+        return Ok(());
+    }
+
+    // Convert with the average of the daily rates over a range of past days
+    if let Some(range) = opts.average {
+        let (rate, count) = history::average_rate(&config, (&from, &to), range)?;
+        let average = ConversionResult {
+            from: &from,
+            to: &to,
+            amount,
+            converted: round_result(amount * rate, opts, &config, &to),
+            rate,
+            timestamp: None,
+        };
+        write_result(&mut out, opts, &config, &average)?;
+        if opts.format == OutputFormat::Text {
+            let (start, end) = range;
+            let plural = if count == 1 { "" } else { "s" };
+            writeln!(
+                out,
+                "(average of {} daily rate{} from {} to {})",
+                count, plural, start, end
+            )?;
+        }
+        return Ok(());
     }
 
+    let rates = load_rates(&config, opts)?;
+
+    if let Some(days) = opts.sparkline {
+        history::write_sparkline(
+            &mut out,
+            &config,
+            &rates,
+            (&from, &to),
+            days,
+            opts.weekend_adjust,
+        )?;
+        return Ok(());
+    }
+
+    // Convert, either directly or leg by leg through the intermediary currency
+    let mut path = vec![from.as_str()];
+    path.extend(opts.via.as_deref());
+    path.push(&to);
+    let mut legs = Vec::new();
+    let mut value = amount;
+    for pair in path.windows(2) {
+        let converted = rates.convert(pair[0], pair[1], value)?;
+        // Distinct currencies sharing a rate hint at bad data from the provider
+        if opts.sanity && pair[0] != pair[1] && rates.rates[pair[0]] == rates.rates[pair[1]] {
+            eprintln!(
+                "Warning: {} and {} have the same rate ({}), the data may be wrong.",
+                pair[0], pair[1], rates.rates[pair[0]]
+            );
+        }
+        legs.push(ConversionResult {
+            from: pair[0],
+            to: pair[1],
+            amount: value,
+            converted,
+            rate: rates.rate(pair[0], pair[1]).unwrap_or_default(),
+            timestamp: Some(rates.timestamp),
+        });
+        value = converted;
+    }
+    if legs.len() > 1 && opts.format == OutputFormat::Text {
+        for leg in &legs {
+            write_result(&mut out, opts, &config, leg)?;
+        }
+    }
+
+    // Print result
+    let result = ConversionResult {
+        from: &from,
+        to: &to,
+        amount,
+        converted: round_result(value, opts, &config, &to),
+        rate: legs.iter().map(|leg| leg.rate).product(),
+        timestamp: Some(rates.timestamp),
+    };
+    write_result(&mut out, opts, &config, &result)?;
+    if opts.fee_tiers {
+        write_fee_tiers(&mut out, opts, &config, &result)?;
+    }
+
+    Ok(())
+}
+
+/// Fees, in percent, compared by --estimate-fee-tiers.
+const FEE_TIERS: [f64; 4] = [0.0, 1.0, 2.5, 5.0];
+
+/// Returns what is left of `amount` once a fee of `percent` is taken from it.
+fn net_of_fee(amount: f64, percent: f64) -> f64 {
+    amount * (1.0 - percent / 100.0)
+}
+
+/// The result net of the common fees, as printed in JSON.
+#[derive(Serialize)]
+struct FeeTiers<'a> {
+    to: &'a str,
+    tiers: Vec<FeeTier>,
+}
+
+/// What is left of the result after a fee of `fee` percent.
+#[derive(Serialize)]
+struct FeeTier {
+    fee: f64,
+    net: f64,
+}
+
+/// Prints the converted amount of `result` net of each of the common fees.
+fn write_fee_tiers(
+    out: &mut dyn Write,
+    opts: &Options,
+    config: &Config,
+    result: &ConversionResult,
+) -> io::Result<()> {
+    let to = result.to;
+    let nets =
+        FEE_TIERS.map(|fee| round_result(net_of_fee(result.converted, fee), opts, config, to));
+    match opts.format {
+        OutputFormat::Json | OutputFormat::PrettyJson => {
+            let tiers = FEE_TIERS
+                .iter()
+                .zip(nets)
+                .map(|(&fee, net)| FeeTier {
+                    fee,
+                    net: config.snap(net),
+                })
+                .collect();
+            writeln!(out, "{}", serde_json::to_string(&FeeTiers { to, tiers })?)
+        }
+        _ => {
+            writeln!(out, "{:<7}Net", "Fee")?;
+            for (fee, net) in FEE_TIERS.iter().zip(nets) {
+                let net = localize(format_converted(net, config.decimals(to), opts), opts);
+                let fee = localize(format!("{}%", fee), opts);
+                writeln!(out, "{:<7}{to} {net}", fee)?;
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Prints every part of a split amount, then their total.
+///
+/// With --reconcile, the parts are rounded so that they add up exactly to the
+/// printed total, instead of being rounded independently.
+fn write_parts(
+    out: &mut dyn Write,
+    opts: &Options,
+    config: &Config,
+    parts: &mut [ConversionResult],
+) -> Result<(), Box<dyn Error>> {
+    let to = parts[0].to;
+    let total: f64 = if opts.reconcile {
+        let converted: Vec<f64> = parts.iter().map(|part| part.converted).collect();
+        let reconciled = currency::reconcile(&converted, config.decimals(to));
+        for (part, converted) in parts.iter_mut().zip(reconciled) {
+            part.converted = converted;
+        }
+        parts.iter().map(|part| part.converted).sum()
+    } else {
+        for part in parts.iter_mut() {
+            part.converted = round_result(part.converted, opts, config, to);
+        }
+        round_result(
+            parts.iter().map(|part| part.converted).sum(),
+            opts,
+            config,
+            to,
+        )
+    };
+    for part in parts.iter() {
+        write_result(out, opts, config, part)?;
+    }
+
+    match opts.format {
+        OutputFormat::Json | OutputFormat::PrettyJson => {
+            let total = JsonTotal {
+                to,
+                total: config.snap(total),
+            };
+            writeln!(out, "{}", serde_json::to_string(&total)?)?
+        }
+        _ => {
+            let total = localize(format_amount(total, config.decimals(to), opts), opts);
+            writeln!(out, "Total = {to} {total}")?
+        }
+    }
+    Ok(())
+}
+
+/// Copies the cached rates to `path`, which must not exist yet, adding the
+/// date they were published to make the snapshot easy to identify.
+fn write_snapshot(cache_path: &PathBuf, path: &Path) -> Result<(), Box<dyn Error>> {
+    let contents = fs::read(cache_path)
+        .map_err(|e| format!("unable to read {} ({})", cache_path.display(), e))?;
+    let mut v: Value = serde_json::from_slice(&contents)?;
+    let rates = RateTable::from_json(&v)?;
+    let published = format_timestamp(rates.timestamp);
+    if let Value::Object(object) = &mut v {
+        object.insert("snapshot".to_string(), json!({ "published": published }));
+    }
+
+    // Snapshots are references, so an existing one is never overwritten
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(path)
+        .map_err(|e| format!("unable to create {} ({})", path.display(), e))?;
+    serde_json::to_writer_pretty(&mut file, &v)?;
+    writeln!(file)?;
+    println!(
+        "Saved the rates of {} currencies published {} to {}.",
+        rates.rates.len(),
+        published,
+        path.display()
+    );
+    Ok(())
+}
+
+/// Prints where the cache is, how old it is, what it contains and whether it
+/// is fresh enough to be used without a refresh.
+fn write_cache_info(out: &mut dyn Write, config: &Config) -> Result<(), Box<dyn Error>> {
+    let path = &config.cache_path;
+    writeln!(out, "{:<14}{}", "path", path.display())?;
+    let Ok(metadata) = fs::metadata(path) else {
+        writeln!(out, "{:<14}no", "exists")?;
+        return Ok(());
+    };
+    writeln!(out, "{:<14}yes", "exists")?;
+    writeln!(out, "{:<14}{} bytes", "size", metadata.len())?;
+    if let Ok(mtime) = metadata.modified()
+        && let Ok(since_epoch) = mtime.duration_since(SystemTime::UNIX_EPOCH)
+    {
+        writeln!(
+            out,
+            "{:<14}{}",
+            "modified",
+            format_timestamp(since_epoch.as_secs())
+        )?;
+    }
+    let age = cache_age(path);
+    if let Some(age) = age {
+        writeln!(out, "{:<14}{}", "age", format_duration(age))?;
+    }
+    let rates = fs::read(path)
+        .map_err(|e| e.to_string())
+        .and_then(|contents| serde_json::from_slice(&contents).map_err(|e| e.to_string()))
+        .and_then(|v| RateTable::from_json(&v));
+    match rates {
+        Ok(rates) => {
+            writeln!(out, "{:<14}{}", "currencies", rates.rates.len())?;
+            writeln!(out, "{:<14}{}", "base", rates.base)?;
+            writeln!(
+                out,
+                "{:<14}{}",
+                "published",
+                format_timestamp(rates.timestamp)
+            )?;
+        }
+        Err(e) => writeln!(out, "{:<14}unreadable ({})", "currencies", e)?,
+    }
+    let pinned = pin_path(path).exists();
+    let fresh = match age {
+        _ if pinned => "yes (pinned)".to_string(),
+        Some(age) if age < config.max_age => {
+            format!("yes (max age {})", format_duration(config.max_age))
+        }
+        _ => format!("no (max age {})", format_duration(config.max_age)),
+    };
+    writeln!(out, "{:<14}{}", "fresh", fresh)?;
+    Ok(())
+}
+
+/// Times a download of the latest rates, a read of the cache and the parsing
+/// of its JSON, and prints how long each took.
+fn benchmark(config: &Config) -> Result<(), Box<dyn Error>> {
+    let url = config.provider.latest_url(&config.api_url, &config.api_key);
+    // Its own stem keeps the scratch file's sidecars, e.g. the download, apart from the cache's
+    let scratch = config.cache_path.with_extension("benchmark.db");
+    let started = Instant::now();
+    let fetched = refresh_rates(config, &url, &scratch);
+    let fetch_time = started.elapsed();
+    _ = fs::remove_file(&scratch);
+    match fetched {
+        Ok(_) => println!("{:<14}{:>10.3} ms", "cold fetch", millis(fetch_time)),
+        Err(e) => println!("{:<14}{:>10}    ({})", "cold fetch", "failed", e),
+    }
+
+    let started = Instant::now();
+    let contents = fs::read(&config.cache_path)?;
+    println!("{:<14}{:>10.3} ms", "cache read", millis(started.elapsed()));
+
+    let started = Instant::now();
+    let rates = RateTable::from_json(&serde_json::from_slice(&contents)?)?;
+    println!(
+        "{:<14}{:>10.3} ms    ({} currencies, {} bytes)",
+        "JSON parse",
+        millis(started.elapsed()),
+        rates.rates.len(),
+        contents.len()
+    );
+    Ok(())
+}
+
+/// Returns a duration in milliseconds, with a fractional part.
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+/// Returns the latest rates, refreshing the cache first if it is too old, or
+/// those of the file given with --rates-file.
+fn load_rates(config: &Config, opts: &Options) -> Result<RateTable, Box<dyn Error>> {
+    if let Some(path) = &opts.rates_file {
+        return read_rates(path);
+    }
+    let file_path = &config.cache_path;
+
+    // Decide if we need to refresh the cache: only if older than max_age, or
+    // filled by another provider than the configured one
+    let other_provider =
+        cached_provider(file_path).is_some_and(|name| name != config.provider.name);
+    let need_refresh = match cache_age(file_path) {
+        Some(_) if pin_path(file_path).exists() => false,
+        Some(_) if other_provider => true,
+        Some(age) => age >= config.max_age,
+        None => true,
+    };
+
+    // Don't retry too soon after a failed refresh, which would likely time out again
+    let need_refresh = need_refresh
+        && match cache_age(&failed_path(file_path)) {
+            Some(age) if age < config.refresh_backoff => {
+                if opts.verbose {
+                    eprintln!(
+                        "Not refreshing the rates, the last attempt failed {} ago.",
+                        format_duration(age)
+                    );
+                }
+                false
+            }
+            _ => true,
+        };
+
     // Refresh from remote API if needed
     if need_refresh {
-        let url = format!("{}?app_id={}", API_URL, API_KEY);
-        match refresh_rates(&url, &file_path) {
-            Ok(_) => {}
+        let url = config.provider.latest_url(&config.api_url, &config.api_key);
+        match refresh_rates(config, &url, file_path) {
+            Ok(outcome) => {
+                _ = fs::remove_file(failed_path(file_path));
+                report_refresh(opts, config, outcome)
+            }
             Err(e) => {
+                if !config.refresh_backoff.is_zero() {
+                    _ = fs::write(failed_path(file_path), "");
+                }
                 eprintln!(
                     "Warning: unable to refresh currency rates ({}). Trying to use previous data.",
                     e
                 );
+                if opts.verbose_errors {
+                    print_causes(e.as_ref());
+                }
             }
         }
     }
 
-    // Read JSON from cache file
-    let json_string = match fs::read_to_string(&file_path) {
-        Ok(contents) => contents,
-        Err(_) => {
+    // Without any cache, e.g. on a first run offline, fall back on the built-in rates
+    if !file_path.exists() {
+        let rates = RateTable::from_json(&serde_json::from_str(FALLBACK_RATES)?)?;
+        let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?;
+        let age = Duration::from_secs(now.as_secs().saturating_sub(rates.timestamp));
+        if let Some(max_stale) = opts.max_stale
+            && age > max_stale
+        {
+            return Err(format!(
+                "no cached rates in {}, and the built-in ones are {} old, more than the allowed {}",
+                file_path.display(),
+                format_duration(age),
+                format_duration(max_stale)
+            )
+            .into());
+        }
+        eprintln!(
+            "Warning: no cached rates in {}, using APPROXIMATE built-in rates from {}.",
+            file_path.display(),
+            format_timestamp(rates.timestamp)
+        );
+        return Ok(rates);
+    }
+
+    // Refuse to fall back on data older than the hard cutoff
+    if let Some(max_stale) = opts.max_stale
+        && let Some(age) = cache_age(file_path)
+        && age > max_stale
+    {
+        return Err(format!(
+            "currency rates in {} are {} old, more than the allowed {}",
+            file_path.display(),
+            format_duration(age),
+            format_duration(max_stale)
+        )
+        .into());
+    }
+
+    let rates = read_rates(file_path)?;
+    check_base(config, &rates)?;
+    Ok(rates)
+}
+
+/// Checks that `rates` are quoted against the expected base currency, which
+/// catches a misconfigured provider or API URL. A mismatch is only a warning
+/// when the expected base is the provider's default, as some accounts allow
+/// choosing another one.
+fn check_base(config: &Config, rates: &RateTable) -> Result<(), String> {
+    if rates.base.is_empty() || rates.base == config.base {
+        return Ok(());
+    }
+    let message = format!(
+        "the rates in {} are based on {}, not {}",
+        config.cache_path.display(),
+        rates.base,
+        config.base
+    );
+    match config.sources["base"] {
+        Source::Default => {
+            eprintln!("Warning: {}.", message);
+            Ok(())
+        }
+        source => Err(format!("{} (expected from {})", message, source)),
+    }
+}
+
+/// Tells what a refresh did with --verbose, and warns about the rates that
+/// moved more than --warn-threshold since the previous ones.
+fn report_refresh(opts: &Options, config: &Config, outcome: Refresh) {
+    if opts.verbose {
+        eprintln!(
+            "{}",
+            match outcome {
+                Refresh::Updated => "Fetched new rates.",
+                Refresh::Unchanged => "Rates unchanged since the last fetch.",
+                Refresh::Kept => {
+                    "The fetched rates aren't newer than the cached ones, keeping those."
+                }
+            }
+        );
+    }
+    if let Some(threshold) = opts.warn_threshold
+        && outcome == Refresh::Updated
+    {
+        warn_movements(config, threshold);
+    }
+}
+
+/// Warns about every currency whose rate moved more than `threshold` percent
+/// between the previous generation of the cache and the current one.
+fn warn_movements(config: &Config, threshold: f64) {
+    let read = |path: &Path| -> Option<RateTable> {
+        let v = serde_json::from_slice(&fs::read(path).ok()?).ok()?;
+        RateTable::from_json(&v).ok()
+    };
+    let (Some(previous), Some(current)) = (
+        read(&previous_path(&config.cache_path)),
+        read(&config.cache_path),
+    ) else {
+        return;
+    };
+    if previous.base != current.base {
+        return;
+    }
+    let mut codes: Vec<&String> = current.rates.keys().collect();
+    codes.sort();
+    for code in codes {
+        let Some(before) = previous.rates.get(code) else {
+            continue;
+        };
+        let change = (current.rates[code] / before - 1.0) * 100.0;
+        if change.abs() > threshold {
             eprintln!(
-                "Error: unable to read currency rates from {}. Verify the file exists and permissions.",
-                file_path.display()
+                "Warning: {} moved by {:+.2}% against {} since the previous rates.",
+                code, change, current.base
             );
-            std::process::exit(1);
         }
-    };
+    }
+}
+
+/// Reads and parses the cached rates, failing if they are unusable.
+fn read_rates(file_path: &PathBuf) -> Result<RateTable, Box<dyn Error>> {
+    // Read JSON from cache file
+    let json_string = fs::read_to_string(file_path).map_err(|_| {
+        format!(
+            "unable to read currency rates from {}. Verify the file exists and permissions.",
+            file_path.display()
+        )
+    })?;
 
     // Parse the JSON, extract "rates"
     let v: Value = serde_json::from_str(&json_string)
         .map_err(|_| "Could not parse JSON from the currency file")?;
-    let rates = match &v["rates"] {
-        Value::Object(_) => &v["rates"],
-        _ => {
-            eprintln!("Error: No 'rates' field found in the JSON data.");
-            std::process::exit(1);
-        }
+    Ok(RateTable::from_json(&v)?)
+}
+
+/// Rounds a converted amount of `to` as requested with --round-to and --financial.
+fn round_result(value: f64, opts: &Options, config: &Config, to: &str) -> f64 {
+    let value = round_to(value, opts.round_to);
+    if opts.financial {
+        round_half_even(value, config.decimals(to))
+    } else {
+        value
+    }
+}
+
+/// Rounds `value` to `decimals` decimals, with ties going to the even digit
+/// (banker's rounding) so that rounding errors don't add up in one direction.
+fn round_half_even(value: f64, decimals: usize) -> f64 {
+    let factor = 10f64.powi(decimals as i32);
+    let scaled = value * factor;
+    // Decimal ties such as 2.675 are rarely exact in binary, so allow for a tiny error
+    let is_tie = (scaled - scaled.floor() - 0.5).abs() < 1e-9 * scaled.abs().max(1.0);
+    let rounded = if !is_tie {
+        scaled.round()
+    } else if scaled.floor() % 2.0 == 0.0 {
+        scaled.floor()
+    } else {
+        scaled.floor() + 1.0
     };
+    rounded / factor
+}
 
-    // Look up the FROM and TO rates
-    let rate_from = match rates.get(&from) {
-        Some(val) => val.as_f64().unwrap_or(0.0),
-        None => {
-            eprintln!("Error: '{}' is not recognized as a currency.", from);
-            std::process::exit(1);
+/// Rounds `value` to the nearest multiple of `step`, if there is one.
+fn round_to(value: f64, step: Option<f64>) -> f64 {
+    match step {
+        Some(step) => (value / step).round() * step,
+        None => value,
+    }
+}
+
+/// Prints a conversion in the requested format.
+fn write_result(
+    out: &mut dyn Write,
+    opts: &Options,
+    config: &Config,
+    conversion: &ConversionResult,
+) -> io::Result<()> {
+    let format = opts.format;
+    let ConversionResult {
+        from,
+        to,
+        amount,
+        converted,
+        ..
+    } = *conversion;
+    if format == OutputFormat::Text && opts.human {
+        let rough = localize(humanize(converted.abs()), opts);
+        let converted = match currency::symbol(to) {
+            Some(symbol) => signed(converted, format!("{}{}", symbol, rough), opts),
+            None => format!("{} {}", to, signed(converted, rough, opts)),
+        };
+        return writeln!(out, "about {}", converted);
+    }
+
+    let style = TextStyle {
+        opts,
+        config,
+        color: opts.use_color(),
+    };
+    if format == OutputFormat::Text {
+        return currency::output::write_result(out, conversion, format, &style);
+    }
+
+    if format == OutputFormat::Fields {
+        let (from_prec, to_prec) = (config.decimals(from), config.decimals(to));
+        let values: Vec<String> = opts
+            .fields
+            .iter()
+            .map(|field| match field {
+                Field::From => from.to_string(),
+                Field::To => to.to_string(),
+                Field::Amount => format_amount(amount, from_prec, opts),
+                Field::Converted => format_converted(converted, to_prec, opts),
+                Field::Rate => conversion.rate.to_string(),
+                Field::Timestamp => conversion
+                    .timestamp
+                    .map(|t| t.to_string())
+                    .unwrap_or_default(),
+            })
+            .collect();
+        return writeln!(out, "{}", values.join("\t"));
+    }
+
+    let snapped = ConversionResult {
+        amount: config.snap(amount),
+        rate: config.snap(conversion.rate),
+        converted: config.snap(converted),
+        ..*conversion
+    };
+    // The age goes after the other fields, whose order is fixed
+    if opts.age
+        && let Some(timestamp) = conversion.timestamp
+    {
+        let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH);
+        let age = now.map_or(0, |now| now.as_secs().saturating_sub(timestamp));
+        let result = AgedResult {
+            result: &snapped,
+            age: format_iso_duration(age),
+        };
+        let json = match format {
+            OutputFormat::PrettyJson => serde_json::to_string_pretty(&result)?,
+            _ => serde_json::to_string(&result)?,
+        };
+        return writeln!(out, "{}", json);
+    }
+    currency::output::write_result(out, &snapped, format, &style)
+}
+
+/// How results are written as text, as requested with the settings and flags.
+struct TextStyle<'a> {
+    opts: &'a Options,
+    config: &'a Config,
+    /// Highlight the codes and the converted amount.
+    color: bool,
+}
+
+impl currency::output::Style for TextStyle<'_> {
+    fn decimals(&self, code: &str) -> usize {
+        self.config.decimals(code)
+    }
+
+    fn code(&self, code: &str) -> String {
+        if self.color {
+            format!("{COLOR_CODE}{code}{COLOR_RESET}")
+        } else {
+            code.to_string()
         }
+    }
+
+    fn amount(&self, value: f64, code: &str) -> String {
+        let formatted = format_amount(value, self.decimals(code), self.opts);
+        localize(formatted, self.opts)
+    }
+
+    fn converted(&self, value: f64, code: &str) -> String {
+        let formatted = format_converted(value, self.decimals(code), self.opts);
+        let converted = localize(formatted, self.opts);
+        if self.color {
+            format!("{COLOR_RESULT}{converted}{COLOR_RESET}")
+        } else {
+            converted
+        }
+    }
+}
+
+/// The total of several conversions into `to`, as printed in JSON.
+#[derive(Serialize)]
+struct JsonTotal<'a> {
+    to: &'a str,
+    total: f64,
+}
+
+/// A conversion in JSON with --age, followed by how long ago its rates were published.
+#[derive(Serialize)]
+struct AgedResult<'a> {
+    #[serde(flatten)]
+    result: &'a ConversionResult<'a>,
+    age: String,
+}
+
+/// Formats a converted value with `prec` decimals, in scientific notation with
+/// --sci if the value is too large or too small to read in fixed notation.
+fn format_converted(value: f64, prec: usize, opts: &Options) -> String {
+    let magnitude = value.abs();
+    let tiny = magnitude < 10f64.powi(-(prec as i32));
+    if opts.sci && magnitude != 0.0 && (magnitude >= 1e9 || tiny) {
+        signed(value, format!("{magnitude:.prec$e}"), opts)
+    } else {
+        format_amount(value, prec, opts)
+    }
+}
+
+/// Formats an amount with `prec` decimals, without the trailing zeros and
+/// decimal point with --precision-auto-trim, e.g. "92.3" rather than "92.3000".
+fn format_amount(value: f64, prec: usize, opts: &Options) -> String {
+    let magnitude = format!("{:.prec$}", value.abs());
+    if opts.trim_zeros {
+        signed(value, trim_zeros(&magnitude).to_string(), opts)
+    } else {
+        signed(value, magnitude, opts)
+    }
+}
+
+/// Gives the sign of `value` to its formatted `magnitude`: a minus or, with
+/// --accounting, parentheses. Amounts that round to zero get no sign.
+fn signed(value: f64, magnitude: String, opts: &Options) -> String {
+    let is_zero = magnitude.chars().all(|c| c == '0' || c == '.');
+    let negative = value < 0.0 && !is_zero;
+    if !negative {
+        magnitude
+    } else if opts.accounting {
+        format!("({})", magnitude)
+    } else {
+        format!("-{}", magnitude)
+    }
+}
+
+/// Writes a formatted number with the separators of the locale, if one was given.
+fn localize(formatted: String, opts: &Options) -> String {
+    match opts.locale {
+        Some(separators) => separators.apply(&formatted),
+        None => formatted,
+    }
+}
+
+/// Removes the trailing zeros of a formatted number, and its decimal point if
+/// nothing is left after it.
+fn trim_zeros(formatted: &str) -> &str {
+    if formatted.contains('.') {
+        formatted.trim_end_matches('0').trim_end_matches('.')
+    } else {
+        formatted
+    }
+}
+
+/// Approximates `value` with three significant digits and a suffix for its
+/// magnitude, e.g. "11.4k" for 11412.5 or "2.5M" for 2503000.
+fn humanize(value: f64) -> String {
+    let mut scaled = value;
+    let mut suffixes = ["", "k", "M", "B", "T"].iter();
+    let mut suffix = suffixes.next().unwrap_or(&"");
+    while scaled.abs() >= 999.5
+        && let Some(next) = suffixes.next()
+    {
+        scaled /= 1000.0;
+        suffix = next;
+    }
+    let decimals = match scaled.abs() {
+        ..10.0 => 2,
+        ..100.0 => 1,
+        _ => 0,
     };
+    format!(
+        "{}{}",
+        trim_zeros(&format!("{:.*}", decimals, scaled)),
+        suffix
+    )
+}
 
-    let rate_to = match rates.get(&to) {
-        Some(val) => val.as_f64().unwrap_or(0.0),
-        None => {
-            eprintln!("Error: '{}' is not recognized as a currency.", to);
+/// Opens the file given with --output-file, or stdout if there is none,
+/// exiting if the file can't be opened.
+fn open_output(opts: &Options) -> Box<dyn Write> {
+    let Some(path) = &opts.output_file else {
+        return Box::new(io::stdout());
+    };
+    let file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .append(opts.append)
+        .truncate(!opts.append)
+        .open(path);
+    match file {
+        Ok(file) => Box::new(file),
+        Err(e) => {
+            eprintln!(
+                "Error: unable to open {} for writing ({}).",
+                path.display(),
+                e
+            );
             std::process::exit(1);
         }
-    };
+    }
+}
 
-    // Convert: (amount / rate_from) * rate_to
-    let converted = (amount / rate_from) * rate_to;
+/// Prints the errors that caused `e`, down to the original one.
+fn print_causes(e: &dyn Error) {
+    let mut source = e.source();
+    while let Some(cause) = source {
+        eprintln!("  caused by: {}", cause);
+        source = cause.source();
+    }
+}
 
-    // Print result
-    println!("{from} {:.4} = {to} {:.4}", amount, converted);
+/// Parses the amount given on the command line, where a comma is the decimal
+/// separator if `decimal_comma` is set.
+fn parse_amount(amount: &str, decimal_comma: bool) -> Result<f64, String> {
+    if !decimal_comma {
+        return Ok(amount.parse().unwrap_or(0.0));
+    }
+    // Periods aren't stripped as thousands separators, as "1.234" would then be ambiguous
+    if amount.contains('.') {
+        return Err(format!(
+            "'{}' contains a period, which is ambiguous with --decimal-comma.",
+            amount
+        ));
+    }
+    amount
+        .replacen(',', ".", 1)
+        .parse()
+        .map_err(|_| format!("'{}' is not a valid amount.", amount))
+}
 
-    Ok(())
+/// Normalizes a currency code as typed by the user, e.g. " usd " becomes "USD".
+fn normalize_code(code: &str) -> String {
+    code.trim().to_uppercase()
+}
+
+/// Returns the file naming the provider the rates cached at `file_path` come from.
+fn source_path(file_path: &Path) -> PathBuf {
+    file_path.with_extension("source")
+}
+
+/// Returns the name of the provider the rates cached at `file_path` come from,
+/// if it was recorded.
+fn cached_provider(file_path: &Path) -> Option<String> {
+    let name = fs::read_to_string(source_path(file_path)).ok()?;
+    Some(name.trim().to_string())
+}
+
+/// Returns the file the cache at `file_path` is copied to before a refresh.
+fn previous_path(file_path: &Path) -> PathBuf {
+    file_path.with_extension("prev")
+}
+
+/// Returns the file written when refreshing the cache at `file_path` failed,
+/// which holds off the next attempts for `refresh_backoff`.
+fn failed_path(file_path: &Path) -> PathBuf {
+    file_path.with_extension("failed")
+}
+
+/// Returns the file whose existence keeps the cache at `file_path` from being refreshed.
+fn pin_path(file_path: &Path) -> PathBuf {
+    file_path.with_extension("pinned")
+}
+
+/// Returns how long ago the cache file was last written, if it exists.
+fn cache_age(file_path: &PathBuf) -> Option<Duration> {
+    let mtime = fs::metadata(file_path).ok()?.modified().ok()?;
+    // Compare modification time with current time
+    SystemTime::now().duration_since(mtime).ok()
+}
+
+fn print_usage() {
+    eprintln!("currency -- Currency converter.");
+    eprintln!("Usage:   currency [OPTIONS] FROM TO [amount...]");
+    eprintln!("         currency [OPTIONS] FROM TO,TO... [amount]");
+    eprintln!("         currency [OPTIONS] FROM TO --sparkline DAYS");
+    eprintln!("         currency [OPTIONS] --portfolio FILE TO");
+    eprintln!("         currency [OPTIONS] --job FILE");
+    eprintln!("         currency [OPTIONS] update");
+    eprintln!("         currency [OPTIONS] doctor");
+    eprintln!("         currency [OPTIONS] --json-stdin");
+    eprintln!("Example: currency USD EUR 123.45");
+    eprintln!("The amount defaults to 1, or the value of --base-amount. Several amounts");
+    eprintln!("are converted one by one, then added up.");
+    eprintln!("Negative amounts, e.g. of refunds, are converted as well.");
+    eprintln!("The update command only refreshes the cache, whatever its age.");
+    eprintln!("The doctor command checks the setup and tells how to fix it.");
+    eprintln!("Options in CURRENCY_OPTS are read before the ones given here.");
+    eprintln!();
+    eprintln!("Options:");
+    eprintln!("  --round-to STEP   Round the result to the nearest multiple of STEP");
+    eprintln!("  --invert          Swap FROM and TO");
+    eprintln!("  --via CODE        Convert through CODE, showing each leg");
+    eprintln!("  --max-stale AGE   Refuse rates older than AGE (e.g. 12h, 7d)");
+    eprintln!("  --output-file PATH");
+    eprintln!("                    Write the result to PATH instead of stdout");
+    eprintln!("  --append          Append to the output file instead of overwriting it");
+    eprintln!("  --decimal-comma   Read the amount with a comma as decimal separator (123,45)");
+    eprintln!("  --financial       Round half to even, to the minor unit of TO (e.g. cents)");
+    eprintln!("  --reconcile       Round several amounts so that they add up to the total");
+    eprintln!("  --locale NAME     Write numbers with the separators of a locale, e.g. de_DE");
+    eprintln!("  --locale-from-env Same with LC_ALL, LC_NUMERIC or LANG, unless --locale is given");
+    eprintln!("  --accounting      Print negative amounts in parentheses, e.g. (92.31)");
+    eprintln!("  --human           Only print roughly what the result is, e.g. \"about €11.4k\"");
+    eprintln!("  --precision-auto-trim");
+    eprintln!("                    Drop trailing zeros, e.g. 92.3 rather than 92.3000");
+    eprintln!("  --sci             Print huge or tiny results in scientific notation");
+    eprintln!("  --base-amount N   Amount converted when none is given (default 1)");
+    eprintln!("  --rate RATE       Convert with RATE units of TO per FROM instead of the");
+    eprintln!("                    cached rates");
+    eprintln!("  --distinct        Refuse to convert a currency into itself");
+    eprintln!("  --sanity          Warn when FROM and TO have suspiciously equal rates");
+    eprintln!("  --portfolio FILE  Value the holdings of a \"currency,amount\" CSV file in TO,");
+    eprintln!("                    at the rates of an optional third YYYY-MM-DD column");
+    eprintln!("  --job FILE        Run the [[conversions]] of a TOML file, with from, to and");
+    eprintln!("                    amount keys");
+    eprintln!("  --fail-on-missing Exit with an error if one of several targets, or a");
+    eprintln!("                    conversion of --portfolio, --job or --json-stdin, fails");
+    eprintln!("  --estimate-fee-tiers");
+    eprintln!("                    Also print the result net of fees of 0%, 1%, 2.5% and 5%");
+    eprintln!("  --jobs N          Convert the rows of --portfolio or --json-stdin on N threads,");
+    eprintln!("                    keeping their order, at most one per core (default 1)");
+    eprintln!("  --color WHEN      Highlight the output: auto (default), always or never");
+    eprintln!("  --no-color        Same as --color never");
+    eprintln!("  --fields LIST     Print only these fields, separated by tabs: from, to,");
+    eprintln!("                    amount, converted, rate and timestamp");
+    eprintln!("  --json            Print the result as a single-line JSON object");
+    eprintln!("  --pretty-json     Print the result as an indented JSON object");
+    eprintln!("  --age             Add how long ago the rates were published to JSON results,");
+    eprintln!("                    as an ISO 8601 duration, e.g. \"age\":\"PT1H23M\"");
+    eprintln!("  --json-stdin      Convert each {{\"from\",\"to\",\"amount\"}} JSON line of stdin");
+    eprintln!("  --average START..END");
+    eprintln!("                    Convert with the average rate over these days, e.g.");
+    eprintln!("                    2024-01-01..2024-01-31");
+    eprintln!("  --sparkline DAYS  Draw the trend of the rate over the last DAYS days, at most");
+    eprintln!("                    366");
+    eprintln!("  --weekend-adjust  Use the rates of the previous day for past days without");
+    eprintln!("                    any, with --sparkline and dated --portfolio rows");
+    eprintln!("  --warn-threshold PERCENT");
+    eprintln!("                    Warn when a refresh moves a rate by more than PERCENT");
+    eprintln!("  --verbose         Tell whether refreshing the cache brought new rates");
+    eprintln!("  --verbose-errors  Show every underlying cause of errors");
+    eprintln!("  --config PATH     Read the configuration from PATH");
+    eprintln!("  --config-dump     Print the effective configuration and exit");
+    eprintln!("  --cache-info      Print the state of the cached rates and exit");
+    eprintln!("  --snapshot PATH   Save a copy of the cached rates to PATH and exit");
+    eprintln!("  --rates-file PATH Convert with the rates saved in PATH, e.g. a snapshot");
+    eprintln!("  --pin-cache       Stop refreshing the cached rates, e.g. during an outage");
+    eprintln!("  --unpin-cache     Refresh the cached rates again when they get old");
+    eprintln!("  --diff-provider A B");
+    eprintln!("                    Compare the rates of FROM and TO from providers A and B");
+    eprintln!("  --print-codes     Print the code of every known currency, one per line");
+    eprintln!("  --country CODE    Print the currencies used in a country, e.g. JP (ISO 3166)");
+    eprintln!("  --list-providers  Print the known providers and exit");
+    eprintln!("  --providers-status");
+    eprintln!("                    Check which providers are reachable and exit");
+    eprintln!();
+    eprintln!("Settings (also CURRENCY_<NAME> variables and config file keys):");
+    eprintln!("  --api-key KEY     Open Exchange Rates API key");
+    eprintln!("  --api-key-file PATH");
+    eprintln!("                    File containing the API key, instead of --api-key");
+    eprintln!("  --api-url URL     Base URL of the provider's API, e.g. a local mock server");
+    eprintln!("  --max-age AGE     Refresh rates older than AGE (default 1h)");
+    eprintln!("  --precision N     Number of decimals in the output, or \"auto\" for the");
+    eprintln!("                    currency's minor unit (default 4)");
+    eprintln!("  --provider NAME   Provider the rates are fetched from: oxr (default) or");
+    eprintln!("                    frankfurter");
+    eprintln!("  --cache-path PATH File the rates are cached in (default ~/.cache/currency.db)");
+    eprintln!("  --epsilon E       Round amounts and rates in JSON to the decimals of E, e.g.");
+    eprintln!("                    1e-9, to hide floating-point artifacts (default 0, never)");
+    eprintln!("  --max-response-size SIZE");
+    eprintln!("                    Refuse responses larger than SIZE, e.g. 512K (default 4M)");
+    eprintln!("  --insecure        Accept invalid TLS certificates, only for test servers");
+    eprintln!("  --refresh-backoff DURATION");
+    eprintln!("                    Don't refresh the rates again for DURATION after a failed");
+    eprintln!("                    refresh (default 0s, always retry)");
+    eprintln!("  --no-refresh-on-error");
+    eprintln!("                    Same as --refresh-backoff 15m");
+    eprintln!("  --base CODE       Currency the rates must be quoted against (default: the");
+    eprintln!("                    provider's, which only warns on a mismatch)");
+}
+
+/// Cooldown after a failed refresh with --no-refresh-on-error.
+const DEFAULT_BACKOFF: &str = "15m";
+
+/// Separates the options from the positional arguments.
+fn parse_args(args: &[String]) -> Result<(Options, Vec<String>), String> {
+    let mut opts = Options::default();
+    let mut positional = Vec::new();
+    let mut locale_from_env = false;
+
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--round-to" => {
+                let value = option_value(&mut iter, arg)?;
+                let step: f64 = value
+                    .parse()
+                    .map_err(|_| format!("invalid step '{}' for {}", value, arg))?;
+                if !step.is_finite() || step <= 0.0 {
+                    return Err(format!("{} must be a positive number", arg));
+                }
+                opts.round_to = Some(step);
+            }
+            "--invert" => opts.invert = true,
+            "--via" => opts.via = Some(normalize_code(option_value(&mut iter, arg)?)),
+            "--output-file" => opts.output_file = Some(option_value(&mut iter, arg)?.into()),
+            "--append" => opts.append = true,
+            "--parse-only" => opts.parse_only = true,
+            "--benchmark" => opts.benchmark = true,
+            "--cache-info" => opts.cache_info = true,
+            "--config" => opts.config_file = Some(option_value(&mut iter, arg)?.into()),
+            "--config-dump" => opts.config_dump = true,
+            "--providers-status" => opts.providers_status = true,
+            "--insecure" => opts.settings.push(("insecure", "true".to_string())),
+            "--no-refresh-on-error" => opts
+                .settings
+                .push(("refresh_backoff", DEFAULT_BACKOFF.to_string())),
+            "--list-providers" => opts.list_providers = true,
+            "--print-codes" => opts.print_codes = true,
+            "--country" => opts.country = Some(option_value(&mut iter, arg)?.trim().to_uppercase()),
+            "--diff-provider" => {
+                let mut next_provider = || {
+                    let name = option_value(&mut iter, arg)?;
+                    provider::find(name)
+                        .ok_or_else(|| format!("unknown provider '{}' for {}", name, arg))
+                };
+                opts.diff_providers = Some([next_provider()?, next_provider()?]);
+            }
+            "--pin-cache" => opts.pin_cache = true,
+            "--snapshot" => opts.snapshot = Some(option_value(&mut iter, arg)?.into()),
+            "--rates-file" => opts.rates_file = Some(option_value(&mut iter, arg)?.into()),
+            "--unpin-cache" => opts.unpin_cache = true,
+            "--verbose-errors" => opts.verbose_errors = true,
+            "--verbose" => opts.verbose = true,
+            "--warn-threshold" => {
+                let value = option_value(&mut iter, arg)?;
+                match value.parse::<f64>() {
+                    Ok(percent) if percent.is_finite() && percent >= 0.0 => {
+                        opts.warn_threshold = Some(percent)
+                    }
+                    _ => return Err(format!("invalid percentage '{}' for {}", value, arg)),
+                }
+            }
+            "--decimal-comma" => opts.decimal_comma = true,
+            "--sci" => opts.sci = true,
+            "--precision-auto-trim" => opts.trim_zeros = true,
+            "--human" => opts.human = true,
+            "--accounting" => opts.accounting = true,
+            "--locale" => {
+                let name = option_value(&mut iter, arg)?;
+                let separators = Separators::for_locale(name)
+                    .ok_or_else(|| format!("unknown locale '{}' for {}", name, arg))?;
+                opts.locale = Some(separators);
+            }
+            "--locale-from-env" => locale_from_env = true,
+            "--reconcile" => opts.reconcile = true,
+            "--financial" => opts.financial = true,
+            "--base-amount" => {
+                let value = option_value(&mut iter, arg)?;
+                let amount = value
+                    .parse()
+                    .map_err(|_| format!("invalid amount '{}' for {}", value, arg))?;
+                opts.base_amount = Some(amount);
+            }
+            "--rate" => {
+                let value = option_value(&mut iter, arg)?;
+                match value.parse::<f64>() {
+                    Ok(rate) if rate.is_finite() && rate > 0.0 => opts.rate = Some(rate),
+                    _ => return Err(format!("invalid rate '{}' for {}", value, arg)),
+                }
+            }
+            "--sanity" => opts.sanity = true,
+            "--distinct" => opts.distinct = true,
+            "--fail-on-missing" => opts.fail_on_missing = true,
+            "--estimate-fee-tiers" => opts.fee_tiers = true,
+            "--jobs" => {
+                let value = option_value(&mut iter, arg)?;
+                match value.parse() {
+                    Ok(jobs @ 1..) => opts.jobs = jobs.min(parallel::max_jobs()),
+                    _ => return Err(format!("invalid number of threads '{}' for {}", value, arg)),
+                }
+            }
+            "--color" => {
+                opts.color = match option_value(&mut iter, arg)?.as_str() {
+                    "auto" => ColorChoice::Auto,
+                    "always" => ColorChoice::Always,
+                    "never" => ColorChoice::Never,
+                    other => return Err(format!("invalid value '{}' for {}", other, arg)),
+                }
+            }
+            "--no-color" => opts.color = ColorChoice::Never,
+            "--job" => opts.job = Some(option_value(&mut iter, arg)?.into()),
+            "--portfolio" => opts.portfolio = Some(option_value(&mut iter, arg)?.into()),
+            "--fields" => {
+                opts.fields = Field::parse_list(option_value(&mut iter, arg)?)?;
+                opts.format = OutputFormat::Fields;
+            }
+            "--json" => opts.format = OutputFormat::Json,
+            "--age" => opts.age = true,
+            "--pretty-json" => opts.format = OutputFormat::PrettyJson,
+            "--json-stdin" => opts.json_stdin = true,
+            "--sparkline" => {
+                let value = option_value(&mut iter, arg)?;
+                match value.parse() {
+                    Ok(days @ 1..) if days <= MAX_HISTORY_DAYS => opts.sparkline = Some(days),
+                    Ok(days @ 1..) => {
+                        return Err(format!(
+                            "{} days is too many for {}, at most {}",
+                            days, arg, MAX_HISTORY_DAYS
+                        ));
+                    }
+                    _ => return Err(format!("invalid number of days '{}' for {}", value, arg)),
+                }
+            }
+            "--weekend-adjust" => opts.weekend_adjust = true,
+            "--average" => opts.average = Some(parse_range(option_value(&mut iter, arg)?)?),
+            "--max-stale" => opts.max_stale = Some(parse_duration(option_value(&mut iter, arg)?)?),
+            flag if flag.starts_with("--") => {
+                // Flags named after a setting override it, e.g. --max-age for max_age
+                let name = flag[2..].replace('-', "_");
+                let Some(key) = config::KEYS.iter().find(|k| **k == name) else {
+                    return Err(format!("unknown option '{}'", flag));
+                };
+                opts.settings
+                    .push((key, option_value(&mut iter, arg)?.clone()));
+            }
+            _ => positional.push(arg.clone()),
+        }
+    }
+
+    // A locale given explicitly wins over the environment's
+    if locale_from_env && opts.locale.is_none() {
+        opts.locale = Separators::from_env();
+    }
+
+    Ok((opts, positional))
+}
+
+/// Most days --sparkline and --average fetch the rates of, with one request each.
+const MAX_HISTORY_DAYS: u32 = 366;
+
+/// Parses a range of days such as "2024-01-01..2024-01-31".
+fn parse_range(range: &str) -> Result<(Date, Date), String> {
+    let invalid = || format!("invalid range '{}', expected START..END", range);
+    let (start, end) = range.split_once("..").ok_or_else(invalid)?;
+    let start: Date = start.parse().map_err(|_| invalid())?;
+    let end: Date = end.parse().map_err(|_| invalid())?;
+    match end.days() - start.days() {
+        ..0 => Err(format!("the range '{}' ends before it starts", range)),
+        days if days >= i64::from(MAX_HISTORY_DAYS) => {
+            Err(format!("the range '{}' is longer than a year", range))
+        }
+        _ => Ok((start, end)),
+    }
+}
+
+/// Returns the value following an option that requires one.
+fn option_value<'a>(
+    iter: &mut impl Iterator<Item = &'a String>,
+    option: &str,
+) -> Result<&'a String, String> {
+    iter.next()
+        .ok_or_else(|| format!("{} requires a value", option))
+}
+
+/// What refreshing the cache did.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Refresh {
+    /// The cache was written with new rates.
+    Updated,
+    /// The fetched rates are the same as the cached ones, only maybe published later.
+    Unchanged,
+    /// The fetched rates aren't newer than the cached ones, which were kept.
+    Kept,
 }
 
 /// Attempts to refresh the local cache file by fetching currency data from the given URL.
-fn refresh_rates(url: &str, file_path: &PathBuf) -> Result<(), Box<dyn Error>> {
+///
+/// The contents of the file are left untouched if the fetched rates aren't newer
+/// than the cached ones from the same provider, only its modification time is
+/// updated to tell when the rates were last fetched. Otherwise the response is
+/// streamed to a temporary file that then replaces the cache at once, so that an
+/// interrupted download never leaves a truncated cache behind.
+fn refresh_rates(
+    config: &Config,
+    url: &str,
+    file_path: &PathBuf,
+) -> Result<Refresh, Box<dyn Error>> {
     // Create parent directories if they don't exist
     if let Some(parent) = file_path.parent() {
         fs::create_dir_all(parent)?;
     }
 
-    let response = reqwest::get(url)?;
+    let client = provider::client(config, provider::FETCH_TIMEOUT)?;
+    let response = client.get(url).send()?;
     if !response.status().is_success() {
         return Err(format!("HTTP request failed with status: {}", response.status()).into());
     }
 
-    let content = response.bytes()?;
+    let temp_path = file_path.with_extension("download");
+    let fetched = match download(response, &temp_path, config.max_response_size) {
+        Ok(fetched) => fetched,
+        Err(e) => {
+            _ = fs::remove_file(&temp_path);
+            return Err(e);
+        }
+    };
 
-    let mut file = OpenOptions::new()
-        .create(true)
-        .write(true)
-        .truncate(true)
-        .open(file_path)?;
+    // Only overwrite the cache with data that is actually newer, which can
+    // only be told of the rates of the same provider against the same base
+    let mut outcome = Refresh::Updated;
+    let same_provider = cached_provider(file_path).as_deref() == Some(config.provider.name);
+    if same_provider
+        && let Ok(cached) = fs::read(file_path)
+        && let Ok(v) = serde_json::from_slice::<Value>(&cached)
+        && let Ok(cached) = RateTable::from_json(&v)
+        && cached.base == fetched.base
+    {
+        if fetched.timestamp <= cached.timestamp {
+            fs::remove_file(&temp_path)?;
+            File::options()
+                .write(true)
+                .open(file_path)?
+                .set_modified(SystemTime::now())?;
+            return Ok(Refresh::Kept);
+        }
+        if fetched.content_hash() == cached.content_hash() {
+            outcome = Refresh::Unchanged;
+        }
+    }
 
-    file.write_all(&content)?;
+    // Keep the previous generation, to compare with --warn-threshold, and
+    // record where the rates come from, only for the latest rates
+    let latest = *file_path == config.cache_path;
+    if latest && file_path.exists() {
+        fs::copy(file_path, previous_path(file_path))?;
+    }
+    fs::rename(&temp_path, file_path)?;
+    if latest {
+        fs::write(source_path(file_path), config.provider.name)?;
+    }
 
-    Ok(())
+    Ok(outcome)
+}
+
+/// Writes the body of `response` to `path` as it arrives, then parses it.
+/// Bodies larger than `limit` bytes are refused, in case of a misbehaving server.
+fn download(
+    response: reqwest::Response,
+    path: &Path,
+    limit: u64,
+) -> Result<RateTable, Box<dyn Error>> {
+    let too_large = || format!("the response is larger than {} bytes", limit);
+    if response
+        .content_length()
+        .is_some_and(|length| length > limit)
+    {
+        return Err(too_large().into());
+    }
+    let mut file = File::create(path)?;
+    // One more byte than allowed tells whether the body goes past the limit
+    if io::copy(&mut response.take(limit + 1), &mut file)? > limit {
+        return Err(too_large().into());
+    }
+    file.sync_all()?;
+
+    let v: Value = serde_json::from_reader(BufReader::new(File::open(path)?))?;
+    Ok(RateTable::from_json(&v)?)
 }
 
+#[cfg(test)]
+mod tests {
+    use std::{env, fs};
+
+    use super::{Config, Options, round_half_even, round_result};
+
+    #[test]
+    fn rounds_ties_to_even() {
+        assert_eq!(round_half_even(2.665, 2), 2.66);
+        assert_eq!(round_half_even(2.675, 2), 2.68);
+        assert_eq!(round_half_even(0.5, 0), 0.0);
+        assert_eq!(round_half_even(1.5, 0), 2.0);
+    }
+
+    #[test]
+    fn rounds_negative_ties_to_even() {
+        assert_eq!(round_half_even(-2.665, 2), -2.66);
+        assert_eq!(round_half_even(-2.675, 2), -2.68);
+    }
+
+    #[test]
+    fn rounds_other_values_to_nearest() {
+        assert_eq!(round_half_even(2.6651, 2), 2.67);
+        assert_eq!(round_half_even(-2.6649, 2), -2.66);
+        assert_eq!(round_half_even(149.5, 2), 149.5);
+    }
+
+    #[test]
+    fn financial_keeps_the_decimals_of_the_config_file() {
+        let file = env::temp_dir().join(format!("currency-test-{}.toml", std::process::id()));
+        fs::write(&file, "[decimals]\nBTC = 8\n").unwrap();
+        let config = Config::load(Some(file.clone()), &[]);
+        fs::remove_file(&file).unwrap();
+        let mut config = config.unwrap();
+        config.financial = true;
+        let opts = Options {
+            financial: true,
+            ..Options::default()
+        };
+        assert_eq!(config.decimals("BTC"), 8);
+        assert_eq!(config.decimals("USD"), 2);
+        assert_eq!(round_result(0.023710871, &opts, &config, "BTC"), 0.02371087);
+        assert_eq!(round_result(90.51945, &opts, &config, "EUR"), 90.52);
+    }
+}
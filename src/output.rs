@@ -0,0 +1,99 @@
+//! Printing of conversion results to any writer, e.g. a buffer rather than
+//! standard output.
+
+use std::io::{self, Write};
+
+use serde::Serialize;
+
+use crate::minor_units;
+
+/// How results are printed.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// "USD 100.00 = EUR 92.31"
+    #[default]
+    Text,
+    /// A JSON object on a single line.
+    Json,
+    /// An indented JSON object.
+    PrettyJson,
+    /// Every field, separated by tabs.
+    Fields,
+}
+
+/// A conversion of `amount` of `from` into `converted` of `to`. In JSON, its
+/// fields are always in this order.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct ConversionResult<'a> {
+    pub from: &'a str,
+    pub to: &'a str,
+    pub amount: f64,
+    /// Units of `to` worth one unit of `from`.
+    pub rate: f64,
+    pub converted: f64,
+    /// Publication time of the rates used, unless none were needed.
+    pub timestamp: Option<u64>,
+}
+
+/// How the codes and amounts of results are written as text. The provided
+/// methods write amounts with the decimals of their currency's minor unit.
+pub trait Style {
+    /// Returns the number of decimals amounts of `code` are written with.
+    fn decimals(&self, code: &str) -> usize {
+        minor_units(code)
+    }
+
+    /// Returns `code` as written in text.
+    fn code(&self, code: &str) -> String {
+        code.to_string()
+    }
+
+    /// Returns `value`, an amount of `code`, as written in text.
+    fn amount(&self, value: f64, code: &str) -> String {
+        format!("{:.*}", self.decimals(code), value)
+    }
+
+    /// Returns `value`, a converted amount of `code`, as written in text.
+    fn converted(&self, value: f64, code: &str) -> String {
+        self.amount(value, code)
+    }
+}
+
+/// The style of the provided methods of `Style`.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct MinorUnits;
+
+impl Style for MinorUnits {}
+
+/// Writes `result` in the format `fmt`, on a line of its own. Amounts in text
+/// and fields have the number of decimals given by `style`.
+pub fn write_result(
+    w: &mut (impl Write + ?Sized),
+    result: &ConversionResult,
+    fmt: OutputFormat,
+    style: &dyn Style,
+) -> io::Result<()> {
+    let (from_prec, to_prec) = (style.decimals(result.from), style.decimals(result.to));
+    match fmt {
+        OutputFormat::Text => writeln!(
+            w,
+            "{} {} = {} {}",
+            style.code(result.from),
+            style.amount(result.amount, result.from),
+            style.code(result.to),
+            style.converted(result.converted, result.to)
+        ),
+        OutputFormat::Json => writeln!(w, "{}", serde_json::to_string(result)?),
+        OutputFormat::PrettyJson => writeln!(w, "{}", serde_json::to_string_pretty(result)?),
+        OutputFormat::Fields => writeln!(
+            w,
+            "{}\t{}\t{:.from_prec$}\t{:.to_prec$}\t{}\t{}",
+            result.from,
+            result.to,
+            result.amount,
+            result.converted,
+            result.rate,
+            result.timestamp.map(|t| t.to_string()).unwrap_or_default()
+        ),
+    }
+}
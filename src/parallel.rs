@@ -0,0 +1,31 @@
+//! Spreading the rows of large batches across threads, for --jobs.
+
+use std::{num::NonZero, panic, thread};
+
+/// Returns how many threads are worth using, at most one per available core.
+pub fn max_jobs() -> usize {
+    thread::available_parallelism().map_or(1, NonZero::get)
+}
+
+/// Returns `f` applied to every item, in order, computed on up to `jobs`
+/// threads that each take a contiguous share of the items.
+pub fn map_ordered<T: Sync, R: Send>(
+    items: &[T],
+    jobs: usize,
+    f: impl Fn(&T) -> R + Sync,
+) -> Vec<R> {
+    if jobs <= 1 || items.len() <= 1 {
+        return items.iter().map(f).collect();
+    }
+    let f = &f;
+    thread::scope(|scope| {
+        let shares: Vec<_> = items
+            .chunks(items.len().div_ceil(jobs))
+            .map(|share| scope.spawn(move || share.iter().map(f).collect::<Vec<R>>()))
+            .collect();
+        shares
+            .into_iter()
+            .flat_map(|share| share.join().unwrap_or_else(|e| panic::resume_unwind(e)))
+            .collect()
+    })
+}
@@ -0,0 +1,190 @@
+//! Valuation of holdings in several currencies, listed in a CSV file.
+
+use std::{
+    collections::{HashMap, hash_map::Entry},
+    error::Error,
+    fs,
+    io::Write,
+    path::Path,
+};
+
+use currency::{
+    RateTable,
+    date::Date,
+    output::{ConversionResult, OutputFormat},
+};
+
+use crate::{
+    JsonTotal, Options, config::Config, format_amount, history, localize, normalize_code, parallel,
+    round_result, write_result,
+};
+
+/// A row of the holdings file, once the rates it needs are fetched.
+enum Row {
+    /// Why a row is left out.
+    Warning(String),
+    /// An amount to value at the latest rates, or at those of `day`.
+    Holding {
+        line: usize,
+        from: String,
+        amount: f64,
+        day: Option<Date>,
+    },
+}
+
+/// Prints the value in `to` of every `currency,amount` row of `holdings`,
+/// followed by their total. Rows may have a third `date` column (YYYY-MM-DD),
+/// to be valued at the rates of that day rather than the latest ones. Rows
+/// that can't be converted are reported and left out of the total, and make
+/// the valuation fail in the end with --fail-on-missing.
+pub fn write_valuation(
+    out: &mut dyn Write,
+    opts: &Options,
+    config: &Config,
+    rates: &RateTable,
+    holdings: &Path,
+    to: &str,
+) -> Result<(), Box<dyn Error>> {
+    let contents = fs::read_to_string(holdings)
+        .map_err(|e| format!("unable to read {} ({})", holdings.display(), e))?;
+
+    // Rows are read and their rates fetched in order, then converted on
+    // --jobs threads, and finally printed in order with their warnings
+    let mut missing = 0;
+    let mut historical = HashMap::new();
+    let mut rows = Vec::new();
+    for (index, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut columns = line.split(',');
+        let code = columns.next().unwrap_or_default();
+        let amount = columns.next().unwrap_or_default();
+        let date = columns
+            .next()
+            .map(str::trim)
+            .filter(|date| !date.is_empty());
+        let from = normalize_code(code);
+        let Ok(amount) = amount.trim().parse::<f64>() else {
+            // The first line may be a header such as "currency,amount"
+            if index > 0 {
+                rows.push(Row::Warning(format!(
+                    "line {}: invalid amount '{}'.",
+                    index + 1,
+                    amount.trim()
+                )));
+            }
+            continue;
+        };
+        // Dated rows are valued at the rates of their day
+        let day = match date {
+            None => None,
+            Some(date) => {
+                let Ok(date) = date.parse::<Date>() else {
+                    rows.push(Row::Warning(format!(
+                        "line {}: invalid date '{}'.",
+                        index + 1,
+                        date
+                    )));
+                    continue;
+                };
+                if let Entry::Vacant(entry) = historical.entry(date) {
+                    match history::fetch_adjusted(config, date, opts.weekend_adjust) {
+                        Ok((day, table)) => {
+                            if day != date {
+                                rows.push(Row::Warning(format!(
+                                    "line {}: no rates for {}, using those of {}.",
+                                    index + 1,
+                                    date,
+                                    day
+                                )));
+                            }
+                            entry.insert(table);
+                        }
+                        Err(e) => {
+                            rows.push(Row::Warning(format!(
+                                "line {}: no rates for {} ({}).",
+                                index + 1,
+                                date,
+                                e
+                            )));
+                            missing += 1;
+                            continue;
+                        }
+                    }
+                }
+                Some(date)
+            }
+        };
+        rows.push(Row::Holding {
+            line: index + 1,
+            from,
+            amount,
+            day,
+        });
+    }
+
+    let valued = parallel::map_ordered(&rows, opts.jobs, |row| -> Result<_, String> {
+        let Row::Holding {
+            line,
+            from,
+            amount,
+            day,
+        } = row
+        else {
+            return Ok(None);
+        };
+        let rates = day.map_or(rates, |day| &historical[&day]);
+        let converted = rates
+            .convert(from, to, *amount)
+            .map_err(|e| format!("line {}: {}", line, e))?;
+        let converted = round_result(converted, opts, config, to);
+        let holding = ConversionResult {
+            from,
+            to,
+            amount: *amount,
+            converted,
+            rate: rates.rate(from, to).unwrap_or_default(),
+            timestamp: Some(rates.timestamp),
+        };
+        let mut printed = Vec::new();
+        write_result(&mut printed, opts, config, &holding).map_err(|e| e.to_string())?;
+        Ok(Some((converted, printed)))
+    });
+
+    let mut total = 0.0;
+    for (row, valued) in rows.iter().zip(valued) {
+        match (row, valued) {
+            (Row::Warning(warning), _) => eprintln!("Warning: {}", warning),
+            (_, Ok(Some((converted, printed)))) => {
+                out.write_all(&printed)?;
+                total += converted;
+            }
+            (_, Ok(None)) => {}
+            (_, Err(e)) => {
+                eprintln!("Warning: {}", e);
+                missing += 1;
+            }
+        }
+    }
+
+    let total = round_result(total, opts, config, to);
+    match opts.format {
+        OutputFormat::Json | OutputFormat::PrettyJson => {
+            let total = JsonTotal {
+                to,
+                total: config.snap(total),
+            };
+            writeln!(out, "{}", serde_json::to_string(&total)?)?
+        }
+        _ => {
+            let total = localize(format_amount(total, config.decimals(to), opts), opts);
+            writeln!(out, "Total = {to} {total}")?
+        }
+    }
+    if opts.fail_on_missing && missing > 0 {
+        return Err(format!("{} of the holdings couldn't be converted", missing).into());
+    }
+    Ok(())
+}
@@ -0,0 +1,219 @@
+//! The services exchange rates can be fetched from.
+
+use std::{
+    error::Error,
+    io::Write,
+    time::{Duration, Instant},
+};
+
+use currency::{
+    RateTable,
+    date::{Date, format_timestamp},
+};
+use reqwest::blocking::Client;
+use serde_json::Value;
+
+use crate::config::Config;
+
+/// The API a provider implements, which determines its URLs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Api {
+    /// https://docs.openexchangerates.org/
+    OpenExchangeRates,
+    /// https://frankfurter.dev/
+    Frankfurter,
+}
+
+/// A source of exchange rates.
+pub struct Provider {
+    /// Name used to select the provider, e.g. with --provider.
+    pub name: &'static str,
+    /// API implemented by the provider.
+    pub api: Api,
+    /// Base URL of the API, which --api-url replaces.
+    pub url: &'static str,
+    /// Whether requests must be authenticated with an API key.
+    pub requires_key: bool,
+    /// Currency the rates are quoted against, unless the account allows another one.
+    pub base: &'static str,
+}
+
+impl Provider {
+    /// Returns the URL of the latest rates under `api_url`, authenticated with `api_key`.
+    pub fn latest_url(&self, api_url: &str, api_key: &str) -> String {
+        let api_url = api_url.trim_end_matches('/');
+        match self.api {
+            Api::OpenExchangeRates => format!("{}/latest.json?app_id={}", api_url, api_key),
+            Api::Frankfurter => format!("{}/latest", api_url),
+        }
+    }
+
+    /// Returns the URL of the rates published on `date`.
+    pub fn historical_url(&self, api_url: &str, api_key: &str, date: Date) -> String {
+        let api_url = api_url.trim_end_matches('/');
+        match self.api {
+            Api::OpenExchangeRates => {
+                format!("{}/historical/{}.json?app_id={}", api_url, date, api_key)
+            }
+            Api::Frankfurter => format!("{}/{}", api_url, date),
+        }
+    }
+}
+
+/// Every provider known to the tool.
+pub const PROVIDERS: &[Provider] = &[
+    Provider {
+        name: "oxr",
+        api: Api::OpenExchangeRates,
+        url: "https://openexchangerates.org/api",
+        requires_key: true,
+        base: "USD",
+    },
+    Provider {
+        name: "frankfurter",
+        api: Api::Frankfurter,
+        url: "https://api.frankfurter.app",
+        requires_key: false,
+        base: "EUR",
+    },
+];
+
+/// Provider used unless another one is configured.
+pub const DEFAULT: &str = "oxr";
+
+/// Time allowed to each provider to answer --providers-status.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Time allowed to download rates.
+pub const FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Returns an HTTP client for the providers, which accepts invalid TLS
+/// certificates if the insecure setting is on.
+pub fn client(config: &Config, timeout: Duration) -> reqwest::Result<Client> {
+    Client::builder()
+        .timeout(timeout)
+        .danger_accept_invalid_certs(config.insecure)
+        .build()
+}
+
+/// Looks up a provider by name.
+pub fn find(name: &str) -> Option<&'static Provider> {
+    PROVIDERS.iter().find(|p| p.name.eq_ignore_ascii_case(name))
+}
+
+/// Prints every provider with its base currency, whether it needs an API key
+/// and its URL. The provider in use is marked with a star.
+pub fn write_list(out: &mut dyn Write, config: &Config) -> std::io::Result<()> {
+    writeln!(out, "{:<14}{:<6}{:<10}URL", "PROVIDER", "BASE", "API KEY")?;
+    for provider in PROVIDERS {
+        let selected = std::ptr::eq(provider, config.provider);
+        let name = format!("{}{}", provider.name, if selected { " *" } else { "" });
+        let key = if provider.requires_key {
+            "required"
+        } else {
+            "-"
+        };
+        writeln!(
+            out,
+            "{:<14}{:<6}{:<10}{}",
+            name, provider.base, key, provider.url
+        )?;
+    }
+    Ok(())
+}
+
+/// Queries every provider for its latest rates and prints whether it is
+/// reachable, how fast it answered and how recent its data is. The provider
+/// in use is marked with a star.
+pub fn write_status(out: &mut dyn Write, config: &Config) -> std::io::Result<()> {
+    writeln!(
+        out,
+        "{:<14}{:<24}{:>9}  DATA",
+        "PROVIDER", "STATUS", "LATENCY"
+    )?;
+    for provider in PROVIDERS {
+        let selected = std::ptr::eq(provider, config.provider);
+        let name = format!("{}{}", provider.name, if selected { " *" } else { "" });
+        if provider.requires_key && config.api_key.is_empty() {
+            writeln!(out, "{:<14}{:<24}{:>9}  -", name, "no API key", "-")?;
+            continue;
+        }
+
+        let started = Instant::now();
+        let status = probe(
+            config,
+            &provider.latest_url(api_url(provider, config), &config.api_key),
+        );
+        let latency = format!("{} ms", started.elapsed().as_millis());
+        match status {
+            Ok(rates) => {
+                let data = format_timestamp(rates.timestamp);
+                writeln!(out, "{:<14}{:<24}{:>9}  {}", name, "ok", latency, data)?
+            }
+            Err(e) => writeln!(out, "{:<14}{:<24}{:>9}  -", name, e, latency)?,
+        }
+    }
+    Ok(())
+}
+
+/// Fetches the latest rates from both `providers` and prints the rate of
+/// `pair` according to each, followed by how much the second one differs.
+pub fn write_diff(
+    out: &mut dyn Write,
+    config: &Config,
+    (from, to): (&str, &str),
+    providers: [&Provider; 2],
+) -> Result<(), Box<dyn Error>> {
+    if let Some(provider) = providers
+        .iter()
+        .find(|p| p.requires_key && config.api_key.is_empty())
+    {
+        return Err(format!("{} requires an API key", provider.name).into());
+    }
+    let mut rates = Vec::new();
+    for provider in providers {
+        let url = provider.latest_url(api_url(provider, config), &config.api_key);
+        let table = probe(config, &url).map_err(|e| format!("{}: {}", provider.name, e))?;
+        let rate = table.lookup(to)? / table.lookup(from)?;
+        rates.push(rate);
+        writeln!(
+            out,
+            "{:<14}{}/{} {:.6}  ({})",
+            provider.name,
+            from,
+            to,
+            rate,
+            format_timestamp(table.timestamp)
+        )?;
+    }
+    let difference = (rates[1] - rates[0]) / rates[0] * 100.0;
+    writeln!(out, "{:<14}{:+.3}%", "difference", difference)?;
+    Ok(())
+}
+
+/// Returns the base URL of the API of `provider`. Only the provider in use is
+/// affected by --api-url.
+fn api_url<'a>(provider: &'a Provider, config: &'a Config) -> &'a str {
+    if std::ptr::eq(provider, config.provider) {
+        &config.api_url
+    } else {
+        provider.url
+    }
+}
+
+/// Fetches the rates at `url`, giving up after a few seconds.
+pub fn probe(config: &Config, url: &str) -> Result<RateTable, String> {
+    let client = client(config, PROBE_TIMEOUT).map_err(|e| e.to_string())?;
+    let response = client.get(url).send().map_err(|e| {
+        if e.is_timeout() {
+            "timed out".to_string()
+        } else {
+            "unreachable".to_string()
+        }
+    })?;
+    if !response.status().is_success() {
+        return Err(format!("HTTP {}", response.status().as_u16()));
+    }
+    let v: Value = response.json().map_err(|_| "invalid JSON".to_string())?;
+    RateTable::from_json(&v).map_err(|_| "no rates".to_string())
+}
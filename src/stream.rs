@@ -0,0 +1,113 @@
+//! Conversions read from stdin and written as line-delimited JSON, for pipelines.
+
+use std::{
+    error::Error,
+    io::{BufRead, Write},
+};
+
+use currency::RateTable;
+use serde_json::{Map, Value, json};
+
+use crate::{Options, config::Config, normalize_code, parallel, round_result};
+
+/// Converts every `{"from":"USD","to":"EUR","amount":100}` line of `input` and
+/// writes it back with the converted amount, rate and timestamp added. Lines
+/// that can't be converted are written with an "error" field instead, and
+/// make the run fail in the end with --fail-on-missing.
+pub fn convert_lines(
+    input: &mut dyn BufRead,
+    out: &mut dyn Write,
+    opts: &Options,
+    config: &Config,
+    rates: &RateTable,
+) -> Result<(), Box<dyn Error>> {
+    // Lines are written as soon as they are converted, unless several threads
+    // convert them, which then take batches of lines
+    let batch_size = match opts.jobs {
+        0 | 1 => 1,
+        jobs => jobs.saturating_mul(BATCH_LINES),
+    };
+    let mut lines = input.lines().enumerate().peekable();
+    let mut missing = 0;
+    while lines.peek().is_some() {
+        let mut batch = Vec::new();
+        for (index, line) in lines.by_ref().take(batch_size) {
+            batch.push((index, line?));
+        }
+        let converted = parallel::map_ordered(&batch, opts.jobs, |(index, line)| {
+            convert_line(*index, line, opts, config, rates)
+        });
+        for (object, failed) in converted.into_iter().flatten() {
+            missing += usize::from(failed);
+            writeln!(out, "{}", object)?;
+        }
+        out.flush()?;
+    }
+    if opts.fail_on_missing && missing > 0 {
+        return Err(format!("{} of the lines couldn't be converted", missing).into());
+    }
+    Ok(())
+}
+
+/// Number of lines each thread converts at a time with --jobs.
+const BATCH_LINES: usize = 1024;
+
+/// Returns the line at `index` of the input with the converted amount added,
+/// or an error, and whether the conversion failed. Blank lines are skipped.
+fn convert_line(
+    index: usize,
+    line: &str,
+    opts: &Options,
+    config: &Config,
+    rates: &RateTable,
+) -> Option<(Value, bool)> {
+    if line.trim().is_empty() {
+        return None;
+    }
+    let mut object = match serde_json::from_str::<Value>(line) {
+        Ok(Value::Object(object)) => object,
+        _ => {
+            let error = format!("line {} is not a JSON object", index + 1);
+            return Some((json!({ "error": error }), true));
+        }
+    };
+    let failed = match convert(&object, opts, config, rates) {
+        Ok(result) => {
+            object.extend(result);
+            false
+        }
+        Err(e) => {
+            object.insert("error".to_string(), e.into());
+            true
+        }
+    };
+    Some((Value::Object(object), failed))
+}
+
+/// Returns the fields added to a converted `object`.
+fn convert(
+    object: &Map<String, Value>,
+    opts: &Options,
+    config: &Config,
+    rates: &RateTable,
+) -> Result<Map<String, Value>, String> {
+    let code = |key: &str| match object.get(key) {
+        Some(Value::String(code)) => Ok(normalize_code(code)),
+        _ => Err(format!("missing '{}' currency", key)),
+    };
+    let (from, to) = (code("from")?, code("to")?);
+    let amount = match object.get("amount") {
+        None => opts.base_amount.unwrap_or(1.0),
+        Some(amount) => amount.as_f64().ok_or("'amount' is not a number")?,
+    };
+    let converted = rates
+        .convert(&from, &to, amount)
+        .map_err(|e| e.to_string())?;
+    let mut result = Map::new();
+    let converted = config.snap(round_result(converted, opts, config, &to));
+    result.insert("converted".to_string(), json!(converted));
+    let rate = rates.rate(&from, &to).map(|rate| config.snap(rate));
+    result.insert("rate".to_string(), json!(rate));
+    result.insert("timestamp".to_string(), json!(rates.timestamp));
+    Ok(result)
+}
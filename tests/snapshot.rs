@@ -0,0 +1,160 @@
+//! Conversions against a captured Open Exchange Rates response, to catch
+//! regressions in the conversion formula or in the formatting of results.
+
+use currency::{
+    ConvertError, RateTable,
+    output::{ConversionResult, MinorUnits, OutputFormat, Style, write_result},
+};
+use serde_json::Value;
+
+fn snapshot() -> RateTable {
+    let json = include_str!("fixtures/oxr-latest.json");
+    let v: Value = serde_json::from_str(json).expect("the fixture is valid JSON");
+    RateTable::from_json(&v).expect("the fixture has rates")
+}
+
+fn convert(from: &str, to: &str, amount: f64) -> String {
+    let converted = snapshot().convert(from, to, amount).unwrap();
+    format!("{:.4}", converted)
+}
+
+#[test]
+fn reads_the_snapshot_metadata() {
+    let rates = snapshot();
+    assert_eq!(rates.base, "USD");
+    assert_eq!(rates.timestamp, 1704067200);
+    assert_eq!(rates.rates.len(), 21);
+}
+
+#[test]
+fn converts_from_the_base_currency() {
+    assert_eq!(convert("USD", "EUR", 100.0), "90.5194");
+    assert_eq!(convert("USD", "BTC", 1000.0), "0.0237");
+}
+
+#[test]
+fn converts_into_the_base_currency() {
+    assert_eq!(convert("EUR", "USD", 100.0), "110.4736");
+    assert_eq!(convert("BTC", "USD", 1.0), "42174.7565");
+}
+
+#[test]
+fn converts_between_cross_rates() {
+    assert_eq!(convert("GBP", "JPY", 250.5), "44972.3770");
+    assert_eq!(convert("EUR", "CHF", 1.0), "0.9298");
+    assert_eq!(convert("JPY", "KRW", 10000.0), "91336.5068");
+    assert_eq!(convert("CNY", "INR", 123.45), "1446.7953");
+    assert_eq!(convert("KWD", "BHD", 10.0), "12.2669");
+    assert_eq!(convert("ZAR", "MXN", 0.0), "0.0000");
+}
+
+#[test]
+fn cross_rate_matches_conversion_of_one_unit() {
+    let rates = snapshot();
+    let rate = rates.rate("EUR", "CHF").unwrap();
+    assert_eq!(format!("{:.4}", rate), convert("EUR", "CHF", 1.0));
+}
+
+#[test]
+fn rejects_unknown_currencies() {
+    let rates = snapshot();
+    assert_eq!(rates.rate("USD", "XXX"), None);
+    assert_eq!(
+        rates.convert("XXX", "USD", 1.0).map_err(|e| e.to_string()),
+        Err("'XXX' is not recognized as a currency.".to_string())
+    );
+}
+
+#[test]
+fn rejects_invalid_rates() {
+    let v = serde_json::json!({ "base": "USD", "rates": { "USD": 1, "EUR": 0, "GBP": "n/a" } });
+    let rates = RateTable::from_json(&v).unwrap();
+    assert_eq!(
+        rates.convert("USD", "EUR", 1.0).map_err(|e| e.to_string()),
+        Err("the rate for 'EUR' is invalid (0).".to_string())
+    );
+    assert_eq!(
+        rates.lookup("GBP").map_err(|e| e.to_string()),
+        Err("the rate for 'GBP' is invalid (\"n/a\").".to_string())
+    );
+}
+
+#[test]
+fn converts_into_each_target_on_its_own() {
+    let v = serde_json::json!({ "base": "USD", "rates": { "USD": 1, "EUR": 0.9, "GBP": 0 } });
+    let rates = RateTable::from_json(&v).unwrap();
+    let results = currency::convert_all(&rates, "USD", 10.0, &["EUR", "XXX", "GBP"]);
+    assert_eq!(results.len(), 3);
+    assert_eq!(results["EUR"], Ok(9.0));
+    assert_eq!(
+        results["XXX"],
+        Err(ConvertError::UnknownCurrency("XXX".to_string()))
+    );
+    assert_eq!(
+        results["GBP"],
+        Err(ConvertError::InvalidRate {
+            code: "GBP".to_string(),
+            value: "0".to_string()
+        })
+    );
+
+    let results = currency::convert_all(&rates, "XXX", 10.0, &["EUR", "USD"]);
+    assert_eq!(results.len(), 2);
+    assert!(
+        results
+            .values()
+            .all(|result| *result == Err(ConvertError::UnknownCurrency("XXX".to_string())))
+    );
+}
+
+fn write(fmt: OutputFormat) -> String {
+    write_styled(fmt, &MinorUnits)
+}
+
+fn write_styled(fmt: OutputFormat, style: &dyn Style) -> String {
+    let rates = snapshot();
+    let result = ConversionResult {
+        from: "USD",
+        to: "JPY",
+        amount: 100.0,
+        rate: rates.rate("USD", "JPY").unwrap(),
+        converted: rates.convert("USD", "JPY", 100.0).unwrap(),
+        timestamp: Some(rates.timestamp),
+    };
+    let mut buffer = Vec::new();
+    write_result(&mut buffer, &result, fmt, style).unwrap();
+    String::from_utf8(buffer).unwrap()
+}
+
+#[test]
+fn writes_results_as_text() {
+    assert_eq!(write(OutputFormat::Text), "USD 100.00 = JPY 14102\n");
+}
+
+/// Four decimals for every currency, as the command line prints by default.
+struct FourDecimals;
+
+impl Style for FourDecimals {
+    fn decimals(&self, _code: &str) -> usize {
+        4
+    }
+}
+
+#[test]
+fn writes_results_with_the_decimals_of_the_style() {
+    assert_eq!(
+        write_styled(OutputFormat::Text, &FourDecimals),
+        "USD 100.0000 = JPY 14102.3500\n"
+    );
+    assert!(
+        write_styled(OutputFormat::Fields, &FourDecimals)
+            .starts_with("USD\tJPY\t100.0000\t14102.3500\t")
+    );
+}
+
+#[test]
+fn writes_results_as_json() {
+    let v: Value = serde_json::from_str(&write(OutputFormat::Json)).unwrap();
+    assert_eq!(v["from"], "USD");
+    assert_eq!(v["timestamp"], 1704067200);
+}